@@ -1,26 +1,81 @@
-use std::ffi::OsString;
+use crate::config::AbortPolicy;
 use crate::latex;
 use crate::logger;
+use crate::selector::SelectorKind;
+use crate::tools::{parse_tool_path, Tool, ToolName};
 use clap::Parser;
-use latex::Engine;
+use latex::{BibBackend, Builder, Capture, Engine, Expander};
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::path::PathBuf;
 
 #[derive(Parser, Clone, Debug)]
 // #[clap(infer_subcommands(true))]
+#[clap(after_help = "Exit codes:
+  0    The diff is built, some tools may have failed with `--abort-if-error continue`
+  1    Invalid args, settings or repo, or any other error
+  2    A tool has failed and the run is aborted
+  130  Interrupted, or no commit is selected")]
 pub struct Args {
-    /// Commit hash of newer version.
+    /// Commit hash of newer version,
+    /// `index` for the staged changes, `worktree` or `.` for the working directory.
     #[clap(long, value_parser, required(false), default_value = None)]
     pub new: Option<String>,
-    /// Commit hash of older version.
+    /// Commit hash of older version,
+    /// `index` for the staged changes, `worktree` or `.` for the working directory.
     #[clap(long, value_parser, required(false), default_value = None)]
     pub old: Option<String>,
+    /// Specify how to pick the versions that are not given by `--old` or `--new`.
+    /// If not given, skim by default, or fzf if built without skim.
+    #[clap(long, value_enum, required(false), env = "GIT_LATEXDIFF_SELECTOR")]
+    pub selector: Option<SelectorKind>,
+    /// Print the preview of a version, which is used by `--selector fzf`
+    #[clap(long, value_parser, required(false), hide = true)]
+    pub preview_item: Option<String>,
     /// Specify the engine that use to compile the documentation.
-    #[clap(long, value_enum, required(false))]
+    /// If not given, pdflatex by default.
+    #[clap(long, value_enum, required(false), env = "GIT_LATEXDIFF_ENGINE")]
     pub engine: Option<Engine>,
+    /// Specify the engine for the older version, overrides `--engine`.
+    #[clap(long, value_enum, required(false))]
+    pub old_engine: Option<Engine>,
+    /// Specify the engine for the newer version, overrides `--engine`.
+    #[clap(long, value_enum, required(false))]
+    pub new_engine: Option<Engine>,
+    /// Specify the engine for the diff result, overrides `--engine`.
+    #[clap(long, value_enum, required(false), env = "GIT_LATEXDIFF_DIFF_ENGINE")]
+    pub diff_engine: Option<Engine>,
+    /// Specify the driver that builds the documentation.
+    /// If not given, native by default.
+    #[clap(long, value_enum, required(false), env = "GIT_LATEXDIFF_BUILDER")]
+    pub builder: Option<Builder>,
+    /// Specify the tool that processes the bibliography.
+    /// If not given, infer from the generated files.
+    #[clap(long, value_enum, required(false), env = "GIT_LATEXDIFF_BIB_BACKEND")]
+    pub bib_backend: Option<BibBackend>,
+    /// Specify the tool that flattens the sources into a single file.
+    /// If not given, native by default.
+    #[clap(long, value_enum, required(false), env = "GIT_LATEXDIFF_EXPANDER")]
+    pub expander: Option<Expander>,
+    /// Maximum number of passes when compiling the diff result,
+    /// stop earlier once the log no longer asks for a rerun.
+    #[clap(long, value_parser, required(false), default_value = "5")]
+    pub max_passes: usize,
     /// Specify the directory to place the intermediate files.
     /// If not given, $PWD/build/tmp by default.
     #[clap(long, short, value_parser, required(false))]
     pub tmp_dir: Option<PathBuf>,
+    /// Place the intermediate files in the temp dir of the system, e.g. /tmp,
+    /// rather than $PWD/build/tmp.
+    #[clap(long, action = clap::ArgAction::SetTrue, default_value = "false", conflicts_with = "tmp_dir")]
+    pub system_tmp: bool,
+    /// Specify the directory to cache the builds of the versions.
+    /// If not given, $XDG_CACHE_HOME/git-latexdiff by default.
+    #[clap(long, value_parser, required(false))]
+    pub cache_dir: Option<PathBuf>,
+    /// Always build the versions from scratch, and do not cache the builds.
+    #[clap(long, action = clap::ArgAction::SetTrue, default_value = "false")]
+    pub no_cache: bool,
     /// Specify the directory to find the git repository.
     /// We will search it's parent until a repo is found.
     /// If not given, $PWD by default.
@@ -29,24 +84,91 @@ pub struct Args {
     /// Specify the tex file to be compiled.
     /// If not given, we will try to guess one.
     /// Most of the time, our guessing is correct.
-    #[clap(long, value_parser, required(false))]
+    #[clap(long, value_parser, required(false), env = "GIT_LATEXDIFF_MAIN_TEX")]
     pub main_tex: Option<PathBuf>,
+    /// Specify the tex file of the older version, overrides `--main-tex`.
+    #[clap(long, value_parser, required(false))]
+    pub old_main_tex: Option<PathBuf>,
+    /// Specify the tex file of the newer version, overrides `--main-tex`.
+    #[clap(long, value_parser, required(false))]
+    pub new_main_tex: Option<PathBuf>,
     /// Target file name to place the diff result,
     /// $PWD/diff.pdf by default.
-    #[clap(long, short, value_parser, required(false))]
+    /// `{old}`, `{new}` and `{date}` in it are replaced by the versions and today.
+    #[clap(
+        long,
+        short,
+        value_parser,
+        required(false),
+        env = "GIT_LATEXDIFF_OUTPUT"
+    )]
     pub output: Option<PathBuf>,
     /// Do not clean the intermediate files.
     #[clap(long, action = clap::ArgAction::SetTrue, default_value = "false")]
     pub no_clean: bool,
+    /// Build the old and new versions one after the other
+    /// instead of at the same time.
+    #[clap(long, action = clap::ArgAction::SetTrue, default_value = "false")]
+    pub no_parallel: bool,
+    /// Specify where the output of the tools goes.
+    /// If not given, quiet by default.
+    #[clap(long, value_enum, required(false), env = "GIT_LATEXDIFF_CAPTURE")]
+    pub capture: Option<Capture>,
+    /// Stream the output of the tools to the terminal,
+    /// same as `--capture stream`.
+    #[clap(long, action = clap::ArgAction::SetTrue, default_value = "false")]
+    pub no_capture: bool,
+    /// Abort if errors occur in the compilation,
+    /// `--abort-if-error` alone is the same as `--abort-if-error any`.
+    #[clap(
+        long,
+        value_enum,
+        required(false),
+        num_args(0..=1),
+        default_value = "continue",
+        default_missing_value = "any"
+    )]
+    pub abort_if_error: AbortPolicy,
     /// Specify the log level
     #[clap(long, value_enum, required(false), default_value = "info")]
     pub log_level: logger::LogLevel,
     /// Specify the path of latexdiff executable
-    #[clap(long, value_parser, required(false))]
+    #[clap(
+        long,
+        value_parser,
+        required(false),
+        env = "GIT_LATEXDIFF_LATEXDIFF_PATH"
+    )]
     pub latexdiff_path: Option<PathBuf>,
+    /// Specify the path of a tool in the form of `<NAME>=<PATH>`,
+    /// e.g. `--tool-path pdflatex=/opt/texlive/bin/pdflatex`.
+    /// Under `--builder latexmk` the engines, bibtex, biber and makeindex
+    /// are handed over to latexmk, makeindex is not run otherwise.
+    #[clap(long, value_parser = parse_tool_path, required(false))]
+    pub tool_path: Vec<(ToolName, PathBuf)>,
+    /// Paths, args and environment of the tools in the config files
+    #[clap(skip)]
+    pub tools: HashMap<ToolName, Tool>,
     /// Extra args that to be passed into latexdiff
     #[clap(long, value_parser, allow_hyphen_values = true, required(false))]
     pub latexdiff_args: Vec<OsString>,
+    /// Extra args that to be passed into the engine when building the old and new versions,
+    /// e.g. `--engine-args=-shell-escape`
+    #[clap(long, value_parser, allow_hyphen_values = true, required(false))]
+    pub engine_args: Vec<OsString>,
+    /// Extra args that to be passed into bibtex or biber, e.g. `--bib-args=--min-crossrefs=1`
+    #[clap(long, value_parser, allow_hyphen_values = true, required(false))]
+    pub bib_args: Vec<OsString>,
+    /// Extra args that to be passed into latexpand, requires `--expander latexpand`
+    #[clap(long, value_parser, allow_hyphen_values = true, required(false))]
+    pub expand_args: Vec<OsString>,
+    /// Extra args that to be passed into the engine when building the diff result
+    #[clap(long, value_parser, allow_hyphen_values = true, required(false))]
+    pub diff_compile_args: Vec<OsString>,
+    /// Use the named profile of the config files,
+    /// e.g. `[profiles.review]` of `.git-latexdiff.toml`.
+    #[clap(long, value_parser, required(false), env = "GIT_LATEXDIFF_PROFILE")]
+    pub profile: Option<String>,
     /// Health Check
     #[clap(long, action = clap::ArgAction::SetTrue, default_value = "false")]
     pub health_check: bool,
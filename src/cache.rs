@@ -0,0 +1,95 @@
+use git2::{ObjectType, Oid};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Persistent store of the compiled and flattened versions,
+/// every entry is addressed by the tree it is built from and the build options.
+///
+/// ```text
+/// <cache_dir>/<key>/main    path of the flattened main tex, relative to `files`
+/// <cache_dir>/<key>/files/  the project after compilation and expansion
+/// ```
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Self {
+        Cache { dir }
+    }
+
+    /// `$XDG_CACHE_HOME/git-latexdiff` or the equivalent of the platform
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("git-latexdiff"))
+    }
+
+    /// Key of the build of `tree`, `options` holds everything else that affects the build
+    pub fn key(tree: Oid, options: &str) -> Oid {
+        let content = format!(
+            "{}\n{}\n{}",
+            env!("CARGO_PKG_VERSION"), // the layout of entries may change between releases
+            tree,
+            options
+        );
+        Oid::hash_object(ObjectType::Blob, content.as_bytes()).unwrap()
+    }
+
+    /// Copy the cached build into `target_dir`, return the flattened main tex in it
+    pub fn restore(&self, key: Oid, target_dir: &Path) -> Option<PathBuf> {
+        let entry = self.dir.join(key.to_string());
+        let main_tex = fs::read_to_string(entry.join("main")).ok()?;
+        if target_dir.exists() {
+            fs::remove_dir_all(target_dir).ok()?;
+        }
+        if let Err(e) = copy_dir(&entry.join("files"), target_dir) {
+            warn!(
+                "Fail to restore the cached build {}: {}",
+                entry.display(),
+                e
+            );
+            return None;
+        }
+        Some(target_dir.join(main_tex.trim_end()))
+    }
+
+    /// Save the build in `dir`, of which the flattened main tex is `main_tex`
+    pub fn store(&self, key: Oid, dir: &Path, main_tex: &Path) {
+        let entry = self.dir.join(key.to_string());
+        if entry.exists() {
+            return;
+        }
+        // fill a partial entry first, so that a concurrent run never sees half of it
+        let partial = self
+            .dir
+            .join(format!("{}.partial-{}", key, std::process::id()));
+        let result = (|| {
+            copy_dir(dir, &partial.join("files"))?;
+            let main_tex = main_tex.strip_prefix(dir).unwrap_or(main_tex);
+            fs::write(partial.join("main"), main_tex.to_string_lossy().as_bytes())?;
+            fs::rename(&partial, &entry)
+        })();
+        match result {
+            Ok(()) => debug!("Build cached in {}", entry.display()),
+            Err(e) => {
+                warn!("Fail to cache the build in {}: {}", entry.display(), e);
+                let _ = fs::remove_dir_all(&partial);
+            }
+        }
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    for dent in WalkDir::new(from) {
+        let dent = dent?;
+        let target = to.join(dent.path().strip_prefix(from).unwrap());
+        match dent.file_type().is_dir() {
+            true => fs::create_dir_all(&target)?,
+            false => {
+                fs::copy(dent.path(), &target)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,57 +1,139 @@
 use crate::args;
+use crate::cache::Cache;
+use crate::error::{Error, ErrorKind};
+use crate::latex::{BibBackend, Builder, Capture, Engine, Expander};
+use crate::selector::SelectorKind;
+use crate::settings::Settings;
+use crate::tools::{ToolName, Tools};
 use args::Args;
 use chrono::prelude::*;
+use clap::ValueEnum;
+use git2::Repository;
 use std::ffi::OsString;
-
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub struct Config {
     pub repo_dir: PathBuf,
     pub tmp_dir: PathBuf,
-    pub latexdiff_path: PathBuf,
+    /// `None` if the cache is disabled
+    pub cache_dir: Option<PathBuf>,
+    pub tools: Tools,
     pub latexdiff_args: Vec<OsString>,
+    /// Extra args of the engine when building the old and new versions
+    pub engine_args: Vec<OsString>,
+    pub bib_args: Vec<OsString>,
+    pub expand_args: Vec<OsString>,
+    /// Extra args of the engine when building the diff result
+    pub diff_compile_args: Vec<OsString>,
     pub output: PathBuf,
-    // FIXME: main tex in different version may differ, fix this
-    pub main_tex: Option<PathBuf>,
+    pub old_engine: Engine,
+    pub new_engine: Engine,
+    pub diff_engine: Engine,
+    pub builder: Builder,
+    pub bib_backend: BibBackend,
+    pub expander: Expander,
+    pub max_passes: usize,
+    pub capture: Capture,
+    pub abort_policy: AbortPolicy,
+    /// Main tex of the older version, relative to the root of repo
+    pub old_main_tex: Option<PathBuf>,
+    /// Main tex of the newer version, relative to the root of repo
+    pub new_main_tex: Option<PathBuf>,
     pub new: Option<String>,
     pub old: Option<String>,
+    pub selector: SelectorKind,
     pub no_clean: bool,
+    pub parallel: bool,
 }
 
-impl From<Args> for Config {
-    fn from(value: Args) -> Self {
+impl TryFrom<Args> for Config {
+    type Error = Error;
+
+    fn try_from(value: Args) -> Result<Self, Self::Error> {
+        let tools = Tools::from(&value);
         ConfigBuilder::default()
             .repo_dir(value.repo_dir)
             .tmp_dir(value.tmp_dir)
-            .latexdiff_path(value.latexdiff_path)
+            .system_tmp(value.system_tmp)
+            .cache_dir(value.cache_dir)
+            .cache(!value.no_cache)
+            .tools(tools)
             .latexdiff_args(value.latexdiff_args)
+            .engine_args(value.engine_args)
+            .bib_args(value.bib_args)
+            .expand_args(value.expand_args)
+            .diff_compile_args(value.diff_compile_args)
             .main_tex(value.main_tex)
+            .old_main_tex(value.old_main_tex)
+            .new_main_tex(value.new_main_tex)
             .output(value.output)
+            .engine(value.engine)
+            .old_engine(value.old_engine)
+            .new_engine(value.new_engine)
+            .diff_engine(value.diff_engine)
+            .builder(value.builder.unwrap_or_default())
+            .bib_backend(value.bib_backend.unwrap_or_default())
+            .expander(value.expander.unwrap_or_default())
+            .max_passes(value.max_passes)
+            .capture(match value.no_capture {
+                true => Capture::Stream,
+                false => value.capture.unwrap_or_default(),
+            })
+            .abort_policy(value.abort_if_error)
             .no_clean(value.no_clean)
+            .parallel(!value.no_parallel)
             .new_hash(value.new)
             .old_hash(value.old)
+            .selector(value.selector.unwrap_or_default())
             .build()
     }
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        ConfigBuilder::default().build()
-    }
+/// When should we stop if any tool fails
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default, ValueEnum)]
+pub enum AbortPolicy {
+    /// Keep going, the diff result may be broken
+    #[default]
+    Continue,
+    /// Abort if any tool fails
+    Any,
+    /// Abort only if the final diff build fails
+    Final,
 }
 
 pub struct ConfigBuilder {
     repo_dir: Option<PathBuf>,
     tmp_dir: Option<PathBuf>,
-    latexdiff_path: Option<PathBuf>,
+    system_tmp: bool,
+    cache_dir: Option<PathBuf>,
+    cache: bool,
+    tools: Tools,
     latexdiff_args: Vec<OsString>,
+    engine_args: Vec<OsString>,
+    bib_args: Vec<OsString>,
+    expand_args: Vec<OsString>,
+    diff_compile_args: Vec<OsString>,
     main_tex: Option<PathBuf>,
+    old_main_tex: Option<PathBuf>,
+    new_main_tex: Option<PathBuf>,
     output: Option<PathBuf>,
+    engine: Option<Engine>,
+    old_engine: Option<Engine>,
+    new_engine: Option<Engine>,
+    diff_engine: Option<Engine>,
+    builder: Builder,
+    bib_backend: BibBackend,
+    expander: Expander,
+    max_passes: usize,
+    capture: Capture,
+    abort_policy: AbortPolicy,
     new: Option<String>,
     old: Option<String>,
+    selector: SelectorKind,
     no_clean: bool,
+    parallel: bool,
 }
 
 impl ConfigBuilder {
@@ -59,67 +141,141 @@ impl ConfigBuilder {
         ConfigBuilder {
             repo_dir: None,
             tmp_dir: None,
-            latexdiff_path: None,
+            system_tmp: false,
+            cache_dir: None,
+            cache: true,
+            tools: Tools::default(),
             latexdiff_args: Vec::new(),
+            engine_args: Vec::new(),
+            bib_args: Vec::new(),
+            expand_args: Vec::new(),
+            diff_compile_args: Vec::new(),
             main_tex: None,
+            old_main_tex: None,
+            new_main_tex: None,
             output: None,
+            engine: None,
+            old_engine: None,
+            new_engine: None,
+            diff_engine: None,
+            builder: Builder::default(),
+            bib_backend: BibBackend::default(),
+            expander: Expander::default(),
+            max_passes: 5,
+            capture: Capture::default(),
+            abort_policy: AbortPolicy::default(),
             new: None,
             old: None,
+            selector: SelectorKind::default(),
             no_clean: false,
+            parallel: true,
         }
     }
 
-    pub fn repo_dir(mut self, path: Option<PathBuf>) -> Self {
-        let mut path = match path {
-            Some(repo_dir) => {
-                // turn all the path to absolute dir
-                match repo_dir.is_absolute() {
-                    true => repo_dir,
-                    false => fs::canonicalize(repo_dir).unwrap_or(
-                        // if the given relative dir does not exist
-                        // fallback to current dir.
-                        std::env::current_dir().unwrap(),
-                    ),
-                }
-            }
-            None => std::env::current_dir().unwrap(),
+    /// Start from the settings of the config files, e.g. those given by [`Settings::load`],
+    /// the setters called afterwards take precedence over them
+    pub fn from_settings(settings: Settings) -> Self {
+        let args = |args: Option<Vec<String>>| -> Vec<OsString> {
+            args.unwrap_or_default()
+                .into_iter()
+                .map(OsString::from)
+                .collect()
         };
-
-        if path.is_file() {
-            path.pop();
+        let mut tools = Tools::new(settings.tools);
+        if let Some(path) = settings.latexdiff_path {
+            tools.set_path(ToolName::Latexdiff, path);
         }
-        self.repo_dir = Some(path);
+        ConfigBuilder::new()
+            .tools(tools)
+            .main_tex(settings.main_tex)
+            .old_main_tex(settings.old_main_tex)
+            .new_main_tex(settings.new_main_tex)
+            .output(settings.output)
+            .engine(settings.engine)
+            .old_engine(settings.old_engine)
+            .new_engine(settings.new_engine)
+            .diff_engine(settings.diff_engine)
+            .builder(settings.builder.unwrap_or_default())
+            .bib_backend(settings.bib_backend.unwrap_or_default())
+            .expander(settings.expander.unwrap_or_default())
+            .capture(settings.capture.unwrap_or_default())
+            .selector(settings.selector.unwrap_or_default())
+            .latexdiff_args(args(settings.latexdiff_args))
+            .engine_args(args(settings.engine_args))
+            .bib_args(args(settings.bib_args))
+            .expand_args(args(settings.expand_args))
+            .diff_compile_args(args(settings.diff_compile_args))
+    }
+
+    /// Directory to search the repo from, $PWD by default
+    pub fn repo_dir(mut self, path: Option<PathBuf>) -> Self {
+        self.repo_dir = path;
+        self
+    }
+
+    /// Directory of the build cache, see [`Cache::default_dir`] for the default one
+    pub fn cache_dir(mut self, path: Option<PathBuf>) -> Self {
+        self.cache_dir = path;
+        self
+    }
 
+    pub fn cache(mut self, on: bool) -> Self {
+        self.cache = on;
         self
     }
 
+    /// Directory to place the intermediate files in, $PWD by default
     pub fn tmp_dir(mut self, path: Option<PathBuf>) -> Self {
-        let mut tmp_dir = match path {
-            Some(dir) => dir,
-            None => std::env::current_dir().unwrap(),
-        };
-        if tmp_dir.is_file() {
-            tmp_dir.pop();
-        }
-        let now: DateTime<Local> = Local::now();
-        // For better compatability
-        tmp_dir.push("build");
-        tmp_dir.push("tmp");
-        tmp_dir.push(format!("git_latexdiff_{}", now.timestamp()));
-        self.tmp_dir = Some(tmp_dir);
+        self.tmp_dir = path;
+        self
+    }
+
+    /// Place the intermediate files in the temp dir of the system,
+    /// which takes precedence over [`ConfigBuilder::tmp_dir`]
+    pub fn system_tmp(mut self, on: bool) -> Self {
+        self.system_tmp = on;
         self
     }
 
+    /// Main tex for both versions, unless overridden by
+    /// [`ConfigBuilder::old_main_tex`] or [`ConfigBuilder::new_main_tex`]
     pub fn main_tex(mut self, path: Option<PathBuf>) -> Self {
         self.main_tex = path;
         self
     }
 
-    pub fn latexdiff_path(mut self, path: Option<PathBuf>) -> Self {
-        self.latexdiff_path = match path {
-            None => Some(which::which("latexdiff").expect("latexdiff not found in $PATH")),
-            Some(_) => path,
+    pub fn old_main_tex(mut self, path: Option<PathBuf>) -> Self {
+        self.old_main_tex = path;
+        self
+    }
+
+    pub fn new_main_tex(mut self, path: Option<PathBuf>) -> Self {
+        self.new_main_tex = path;
+        self
+    }
+
+    /// Turn the path given by user into the path relative to the root of repo
+    fn relative_to_repo(repo_dir: &Path, path: PathBuf) -> PathBuf {
+        let absolute = match path.is_absolute() {
+            true => path.clone(),
+            false => repo_dir.join(&path),
         };
+        let root = Repository::discover(repo_dir)
+            .ok()
+            .and_then(|repo| repo.workdir().map(|dir| dir.to_path_buf()));
+        match root {
+            Some(root) => match absolute.strip_prefix(&root) {
+                Ok(relative) => relative.to_path_buf(),
+                // the file may be missing in the working tree, e.g. renamed
+                Err(_) => path,
+            },
+            None => path,
+        }
+    }
+
+    /// Paths, args and environment of the external tools
+    pub fn tools(mut self, tools: Tools) -> Self {
+        self.tools = tools;
         self
     }
 
@@ -128,6 +284,83 @@ impl ConfigBuilder {
         self
     }
 
+    /// Extra args of the engine when building the old and new versions,
+    /// see [`ConfigBuilder::diff_compile_args`] for the diff result
+    pub fn engine_args(mut self, args: Vec<OsString>) -> Self {
+        self.engine_args = args;
+        self
+    }
+
+    /// Extra args of bibtex or biber
+    pub fn bib_args(mut self, args: Vec<OsString>) -> Self {
+        self.bib_args = args;
+        self
+    }
+
+    /// Extra args of latexpand
+    pub fn expand_args(mut self, args: Vec<OsString>) -> Self {
+        self.expand_args = args;
+        self
+    }
+
+    pub fn diff_compile_args(mut self, args: Vec<OsString>) -> Self {
+        self.diff_compile_args = args;
+        self
+    }
+
+    /// Engine for all the versions, unless overridden by
+    /// [`ConfigBuilder::old_engine`], [`ConfigBuilder::new_engine`]
+    /// or [`ConfigBuilder::diff_engine`]
+    pub fn engine(mut self, engine: Option<Engine>) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    pub fn old_engine(mut self, engine: Option<Engine>) -> Self {
+        self.old_engine = engine;
+        self
+    }
+
+    pub fn new_engine(mut self, engine: Option<Engine>) -> Self {
+        self.new_engine = engine;
+        self
+    }
+
+    pub fn diff_engine(mut self, engine: Option<Engine>) -> Self {
+        self.diff_engine = engine;
+        self
+    }
+
+    pub fn builder(mut self, builder: Builder) -> Self {
+        self.builder = builder;
+        self
+    }
+
+    pub fn bib_backend(mut self, backend: BibBackend) -> Self {
+        self.bib_backend = backend;
+        self
+    }
+
+    pub fn expander(mut self, expander: Expander) -> Self {
+        self.expander = expander;
+        self
+    }
+
+    pub fn max_passes(mut self, passes: usize) -> Self {
+        self.max_passes = passes;
+        self
+    }
+
+    pub fn capture(mut self, capture: Capture) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    pub fn abort_policy(mut self, policy: AbortPolicy) -> Self {
+        self.abort_policy = policy;
+        self
+    }
+
     pub fn new_hash(mut self, hash: Option<String>) -> Self {
         self.new = hash;
         self
@@ -138,61 +371,224 @@ impl ConfigBuilder {
         self
     }
 
+    /// How to pick the versions that are not given
+    pub fn selector(mut self, selector: SelectorKind) -> Self {
+        self.selector = selector;
+        self
+    }
+
     pub fn no_clean(mut self, on: bool) -> Self {
         self.no_clean = on;
         self
     }
 
+    /// Build the old and new versions at the same time
+    pub fn parallel(mut self, on: bool) -> Self {
+        self.parallel = on;
+        self
+    }
+
+    /// $PWD/diff.pdf by default, `path` may be a template,
+    /// see [`Runner::output`](crate::runner::Runner::output)
     pub fn output(mut self, path: Option<PathBuf>) -> Self {
-        let mut path = match path {
-            Some(path) => path,
-            None => std::env::current_dir().unwrap(),
-        };
+        self.output = path;
+        self
+    }
 
-        let file_name = match path.is_file() {
-            true => OsString::from(path.file_name().unwrap()),
-            false => OsString::from("diff.pdf"),
+    fn resolve_repo_dir(path: Option<PathBuf>, cwd: &Path) -> Result<PathBuf, Error> {
+        let path = match path {
+            Some(path) => cwd.join(path),
+            None => return Ok(cwd.to_path_buf()),
         };
-
+        // turn all the path to absolute dir
+        let mut path = fs::canonicalize(&path)
+            .map_err(|_| Error::new(ErrorKind::RepoNotFound(path.clone())))?;
         if path.is_file() {
             path.pop();
         }
+        Ok(path)
+    }
+
+    fn resolve_tmp_dir(
+        path: Option<PathBuf>,
+        system_tmp: bool,
+        cwd: &Path,
+    ) -> Result<PathBuf, Error> {
+        let now: DateTime<Local> = Local::now();
+        if system_tmp {
+            // several runs may share the system temp dir
+            return Ok(std::env::temp_dir().join(format!(
+                "git_latexdiff_{}_{}",
+                now.timestamp(),
+                std::process::id()
+            )));
+        }
+        let mut tmp_dir = match path {
+            Some(dir) => cwd.join(dir),
+            None => cwd.to_path_buf(),
+        };
+        if tmp_dir.is_file() {
+            tmp_dir.pop();
+        }
+        if !ConfigBuilder::creatable(&tmp_dir) {
+            return Err(Error::new(ErrorKind::InvalidTmpDir(tmp_dir)));
+        }
+        // For better compatability
+        tmp_dir.push("build");
+        tmp_dir.push("tmp");
+        tmp_dir.push(format!("git_latexdiff_{}", now.timestamp()));
+        Ok(tmp_dir)
+    }
 
-        // turn to absolute
-        if !path.is_absolute() {
-            path = fs::canonicalize(path).unwrap()
+    fn resolve_output(path: Option<PathBuf>, cwd: &Path) -> Result<PathBuf, Error> {
+        let mut path = match path {
+            Some(path) => cwd.join(path),
+            None => cwd.to_path_buf(),
+        };
+        // a path with an extension names the file, which may not exist yet
+        if path.is_dir() || path.extension().is_none() {
+            path.push("diff.pdf");
         }
-        path.push(file_name);
+        if path.is_dir() || !ConfigBuilder::creatable(path.parent().unwrap()) {
+            return Err(Error::new(ErrorKind::InvalidOutputPath(path)));
+        }
+        Ok(path)
+    }
 
-        self.output = Some(path);
-        self
+    /// Whether `dir` exists or can be created, i.e. its closest existing ancestor is a directory
+    fn creatable(dir: &Path) -> bool {
+        match dir.ancestors().find(|path| path.exists()) {
+            Some(ancestor) => ancestor.is_dir(),
+            None => false,
+        }
     }
 
-    pub fn build(self) -> Config {
-        Config {
-            repo_dir: self.repo_dir.unwrap(),
-            tmp_dir: self.tmp_dir.unwrap(),
-            main_tex: self.main_tex,
-            latexdiff_path: self.latexdiff_path.unwrap(),
+    /// Make sure the tools that are going to be used are installed
+    fn check_binaries(&self, engines: &[Engine]) -> Result<(), Error> {
+        self.tools.resolve(ToolName::Latexdiff)?;
+        // latexmk runs the engines of the old, new and diff builds in turn
+        if self.builder == Builder::Latexmk {
+            self.tools.resolve(ToolName::Latexmk)?;
+        }
+        for engine in engines {
+            self.tools.resolve(engine.tool())?;
+        }
+        // `auto` is resolved later, only the one given explicitly can be checked
+        match self.bib_backend {
+            BibBackend::Bibtex => self.tools.resolve(ToolName::Bibtex).map(|_| ())?,
+            BibBackend::Biber => self.tools.resolve(ToolName::Biber).map(|_| ())?,
+            BibBackend::Auto => {}
+        }
+        if self.expander == Expander::Latexpand {
+            self.tools.resolve(ToolName::Latexpand)?;
+        }
+        Ok(())
+    }
+
+    pub fn build(mut self) -> Result<Config, Error> {
+        let cwd = std::env::current_dir()
+            .map_err(|e| Error::new(ErrorKind::IoError(PathBuf::from("."), e.to_string())))?;
+        let repo_dir = ConfigBuilder::resolve_repo_dir(self.repo_dir.clone(), &cwd)?;
+        let tmp_dir = ConfigBuilder::resolve_tmp_dir(self.tmp_dir.clone(), self.system_tmp, &cwd)?;
+        let output = ConfigBuilder::resolve_output(self.output.clone(), &cwd)?;
+        self.tools.root_at(&cwd);
+        // fail before anything is built, rather than when the sources are expanded
+        if !self.expand_args.is_empty() && self.expander != Expander::Latexpand {
+            return Err(Error::new(ErrorKind::InvalidSettings(
+                String::from("--expand-args"),
+                String::from("they are only taken by `--expander latexpand`"),
+            )));
+        }
+
+        let old_engine = self.old_engine.or(self.engine).unwrap_or_default();
+        let new_engine = self.new_engine.or(self.engine).unwrap_or_default();
+        let diff_engine = self.diff_engine.or(self.engine).unwrap_or_default();
+        self.check_binaries(&[old_engine, new_engine, diff_engine])?;
+
+        let old_main_tex = self
+            .old_main_tex
+            .or(self.main_tex.clone())
+            .map(|path| ConfigBuilder::relative_to_repo(&repo_dir, path));
+        let new_main_tex = self
+            .new_main_tex
+            .or(self.main_tex)
+            .map(|path| ConfigBuilder::relative_to_repo(&repo_dir, path));
+
+        Ok(Config {
+            repo_dir,
+            tmp_dir,
+            cache_dir: match self.cache {
+                true => self.cache_dir.or_else(Cache::default_dir),
+                false => None,
+            },
+            old_main_tex,
+            new_main_tex,
+            tools: self.tools,
             latexdiff_args: self.latexdiff_args,
-            output: self.output.unwrap(),
+            engine_args: self.engine_args,
+            bib_args: self.bib_args,
+            expand_args: self.expand_args,
+            diff_compile_args: self.diff_compile_args,
+            output,
+            old_engine,
+            new_engine,
+            diff_engine,
+            builder: self.builder,
+            bib_backend: self.bib_backend,
+            expander: self.expander,
+            max_passes: self.max_passes,
+            capture: self.capture,
+            abort_policy: self.abort_policy,
             new: self.new,
             old: self.old,
+            selector: self.selector,
             no_clean: self.no_clean,
-        }
+            parallel: self.parallel,
+        })
     }
 }
 
 impl Default for ConfigBuilder {
     fn default() -> Self {
-        let builder = ConfigBuilder::new()
-            .repo_dir(None)
-            .tmp_dir(None)
-            .latexdiff_path(None)
-            .main_tex(None)
-            .output(None)
-            .no_clean(false);
-
-        builder
+        ConfigBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn setters_take_precedence_over_settings() {
+        let settings = Settings {
+            engine: Some(Engine::Xelatex),
+            builder: Some(Builder::Latexmk),
+            latexdiff_path: Some(PathBuf::from("/opt/latexdiff")),
+            latexdiff_args: Some(vec![String::from("--type=CFONT")]),
+            ..Settings::default()
+        };
+        let builder = ConfigBuilder::from_settings(settings)
+            .engine(Some(Engine::Lualatex))
+            .latexdiff_args(Vec::new());
+
+        assert_eq!(builder.engine, Some(Engine::Lualatex));
+        assert_eq!(builder.builder, Builder::Latexmk);
+        assert!(builder.latexdiff_args.is_empty());
+        assert_eq!(
+            builder.tools.path(ToolName::Latexdiff),
+            PathBuf::from("/opt/latexdiff")
+        );
+    }
+
+    #[test]
+    fn expand_args_need_latexpand() {
+        let dir = tempfile::TempDir::new().unwrap();
+        Repository::init(dir.path()).unwrap();
+        let err = ConfigBuilder::new()
+            .repo_dir(Some(dir.path().to_path_buf()))
+            .expand_args(vec![OsString::from("--keep-comments")])
+            .build()
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::InvalidSettings(..)));
     }
 }
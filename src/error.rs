@@ -1,3 +1,4 @@
+use crate::texlog::Diagnostic;
 use std::error;
 use std::fmt;
 use std::path::PathBuf;
@@ -24,8 +25,15 @@ impl Error {
 pub enum ErrorKind {
     /// String here is to represent the name of binary
     BinaryNotFound(String),
-    /// String here is to represent the name of task
-    CompileError(String),
+    /// A tool fails in the compilation
+    CompileError {
+        /// Name of the tool, e.g. pdflatex, bibtex
+        stage: String,
+        /// Which version is being built, e.g. old, new or diff
+        version: String,
+        /// Errors found in the log
+        diagnostics: Vec<Diagnostic>,
+    },
     /// If user abort the skim when selecting commit id
     /// this error occurs
     SkimAbort,
@@ -36,12 +44,32 @@ pub enum ErrorKind {
     /// can not be inferred (search `\documentclass`)
     /// this error occurs
     MainTeXNotFound,
-    /// Functionality is not support on current device,
-    /// For example, interactive mode is not support on windows target
-    /// since skim does not support windows
-    NotSupportedDevice,
+    /// More than one TeX files are equally likely to be the main TeX,
+    /// Vec here is to represent the candidates
+    AmbiguousMainTeX(Vec<PathBuf>),
+    /// String here is to represent the selector given by `--selector`,
+    /// which can not pick the versions
+    SelectorNotAvailable(String),
+    /// PathBuf here is to represent the `.bbl` file that
+    /// neither bibtex nor biber has produced
+    BblNotFound(PathBuf),
+    /// Files referred by `\input` and friends that do not exist
+    InputNotFound(Vec<PathBuf>),
+    /// The build is stopped since the other version has failed
+    Cancelled,
+    /// The profile given by user is in none of the config files
+    ProfileNotFound(String),
+    /// The config file or the git config key that can not be parsed, and the reason
+    InvalidSettings(String, String),
+    /// The output path which is a directory, or can not be created
+    InvalidOutputPath(PathBuf),
+    /// PathBuf here is to represent the tmp dir that can not be created
+    InvalidTmpDir(PathBuf),
     /// The commit hash given by user is invalid
     InvalidCommitHash,
+    /// PathBuf here is to represent the file or directory that
+    /// can not be read or written, and String is the reason
+    IoError(PathBuf, String),
     /// Some errors that haven't had a name
     UncategorizedError,
     /// Hints that destructuring should not be exhaustive.
@@ -57,12 +85,21 @@ impl error::Error for Error {
     fn description(&self) -> &str {
         match self.kind {
             ErrorKind::BinaryNotFound(_) => "Executable binary not given and not found in $PATH",
-            ErrorKind::CompileError(_) => "Error occurs in compilation.",
+            ErrorKind::CompileError { .. } => "Error occurs in compilation.",
             ErrorKind::SkimAbort => "Abort occurs in skim",
             ErrorKind::RepoNotFound(_) => "Repository not given and not found in $PWD",
             ErrorKind::MainTeXNotFound => "Main TeX not given and can not be inferred",
-            ErrorKind::NotSupportedDevice => "Not supported device",
+            ErrorKind::AmbiguousMainTeX(_) => "Main TeX not given and is ambiguous",
+            ErrorKind::SelectorNotAvailable(_) => "Selector is not available",
+            ErrorKind::BblNotFound(_) => "Bibliography is not produced",
+            ErrorKind::InputNotFound(_) => "Input files not found",
+            ErrorKind::Cancelled => "Build cancelled",
+            ErrorKind::ProfileNotFound(_) => "Profile not found",
+            ErrorKind::InvalidSettings(_, _) => "Invalid settings",
+            ErrorKind::InvalidOutputPath(_) => "Invalid output path",
+            ErrorKind::InvalidTmpDir(_) => "Invalid tmp dir",
             ErrorKind::InvalidCommitHash => "Invalid commit hash",
+            ErrorKind::IoError(_, _) => "I/O error",
             ErrorKind::UncategorizedError => "Uncategorized error",
             ErrorKind::__NonExhaustive => unreachable!(),
         }
@@ -73,10 +110,26 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.kind {
             ErrorKind::BinaryNotFound(ref name) => {
-                write!(f, "'{}' is not given and not found in $PATH", name)
+                write!(
+                    f,
+                    "'{}' is not found, install it or specify its path with --tool-path",
+                    name
+                )
             }
-            ErrorKind::CompileError(ref task) => {
-                write!(f, "errors occurs in the {} compilation", task)
+            ErrorKind::CompileError {
+                ref stage,
+                ref version,
+                ref diagnostics,
+            } => {
+                write!(
+                    f,
+                    "errors occurs in the {} stage of the {} version",
+                    stage, version
+                )?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n  {}", diagnostic)?;
+                }
+                Ok(())
             }
             ErrorKind::SkimAbort => {
                 write!(f, "abort occurs in the selecting commits")
@@ -87,12 +140,55 @@ impl fmt::Display for Error {
             ErrorKind::MainTeXNotFound => {
                 write!(f, "Main TeX not given and can not be inferred")
             }
-            ErrorKind::NotSupportedDevice => {
-                write!(f, "Not supported device")
+            ErrorKind::AmbiguousMainTeX(ref candidates) => {
+                write!(
+                    f,
+                    "Main TeX not given and can not be told from the candidates, \
+                    specify one with --main-tex:"
+                )?;
+                for candidate in candidates {
+                    write!(f, "\n  {}", candidate.display())?;
+                }
+                Ok(())
+            }
+            ErrorKind::SelectorNotAvailable(ref selector) => {
+                write!(
+                    f,
+                    "Selector '{}' is not available, specify the versions by --old and --new",
+                    selector
+                )
+            }
+            ErrorKind::BblNotFound(ref path) => {
+                write!(f, "neither bibtex nor biber produced {}", path.display())
+            }
+            ErrorKind::InputNotFound(ref files) => {
+                write!(f, "Can not find the input files when flattening:")?;
+                for file in files {
+                    write!(f, "\n  {}", file.display())?;
+                }
+                Ok(())
+            }
+            ErrorKind::Cancelled => {
+                write!(f, "Build cancelled")
+            }
+            ErrorKind::ProfileNotFound(ref profile) => {
+                write!(f, "Profile {} is not found in the config files", profile)
+            }
+            ErrorKind::InvalidSettings(ref source, ref reason) => {
+                write!(f, "Invalid settings in {}: {}", source, reason)
+            }
+            ErrorKind::InvalidOutputPath(ref path) => {
+                write!(f, "Can not place the diff result at {}", path.display())
+            }
+            ErrorKind::InvalidTmpDir(ref path) => {
+                write!(f, "Can not create the tmp dir {}", path.display())
             }
             ErrorKind::InvalidCommitHash => {
                 write!(f, "Invalid commit hash")
             }
+            ErrorKind::IoError(ref path, ref reason) => {
+                write!(f, "Can not access {}: {}", path.display(), reason)
+            }
             ErrorKind::UncategorizedError => {
                 write!(f, "Uncategorized error")
             }
@@ -0,0 +1,387 @@
+use regex::{Captures, Regex};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Files nested deeper than this are considered to be a cycle of inputs
+const MAX_DEPTH: usize = 32;
+
+/// Inline the files of a LaTeX project into a single file,
+/// an in-process replacement of `latexpand`
+pub struct Flattener {
+    /// The directory that TeX resolves the relative paths against
+    root: PathBuf,
+    /// Inline it in place of `\bibliography`
+    bbl: Option<PathBuf>,
+    /// Files listed in `\includeonly`, `None` if every file is included
+    includeonly: Option<Vec<String>>,
+    /// Files that are referred but can not be found
+    missing: Vec<PathBuf>,
+    pattern: Regex,
+}
+
+impl Flattener {
+    pub fn new(root: PathBuf) -> Self {
+        Flattener {
+            root,
+            bbl: None,
+            includeonly: None,
+            missing: Vec::new(),
+            // only `\import` and `\subimport` take a second argument, a brace group
+            // following the other commands is left to TeX
+            pattern: Regex::new(
+                r"\\(?:(?P<import>import|subimport)\s*\{(?P<dir>[^}]*)\}\s*\{(?P<file>[^}]*)\}|(?P<command>input|include|includeonly|subfile|import|subimport|bibliography|bibliographystyle|endinput)\b(?:\s*\{(?P<arg>[^}]*)\}|[ \t]+(?P<word>[^\s{}\\%]+))?)",
+            )
+            .unwrap(),
+        }
+    }
+
+    pub fn bbl(mut self, bbl: Option<PathBuf>) -> Self {
+        self.bbl = bbl;
+        self
+    }
+
+    /// Files that are referred by the project but can not be found,
+    /// the commands referring to them are left untouched
+    pub fn missing(&self) -> &[PathBuf] {
+        &self.missing
+    }
+
+    /// Flatten `file` and all the files it refers to
+    pub fn flatten(&mut self, file: &Path) -> io::Result<String> {
+        let content = read(file)?;
+        let root = self.root.clone();
+        Ok(self.expand_text(&content, &root, 0))
+    }
+
+    fn expand_text(&mut self, text: &str, base: &Path, depth: usize) -> String {
+        let mut out = String::with_capacity(text.len());
+
+        for line in text.split_inclusive('\n') {
+            let (code, comment) = split_comment(line);
+            let mut end_input = false;
+            let pattern = self.pattern.clone();
+            let code = pattern.replace_all(code, |caps: &Captures| {
+                if end_input {
+                    // the rest of the line is still read by TeX, but not the commands
+                    return caps[0].to_string();
+                }
+                if let Some(import) = caps.name("import") {
+                    let (dir, file) = (caps["dir"].trim(), caps["file"].trim());
+                    // `\import` is relative to the root, `\subimport` to the current file
+                    let dir = match import.as_str() {
+                        "import" => self.root.join(dir),
+                        _ => base.join(dir),
+                    };
+                    return self.inline(&caps[0], &dir, file, depth);
+                }
+                let arg = caps
+                    .name("arg")
+                    .or(caps.name("word"))
+                    .map(|arg| arg.as_str().trim());
+                match (&caps["command"], arg) {
+                    ("endinput", _) => {
+                        end_input = true;
+                        String::new()
+                    }
+                    ("input", Some(arg)) => self.inline(&caps[0], base, arg, depth),
+                    ("include", Some(arg)) => match &self.includeonly {
+                        Some(only) if !only.iter().any(|name| name == arg) => {
+                            String::from("\\clearpage{}")
+                        }
+                        _ => format!(
+                            "\\clearpage{{}}{}\\clearpage{{}}",
+                            self.inline(&caps[0], base, arg, depth)
+                        ),
+                    },
+                    ("includeonly", Some(arg)) => {
+                        self.includeonly = Some(
+                            arg.split(',')
+                                .map(|name| name.trim().to_string())
+                                .filter(|name| !name.is_empty())
+                                .collect(),
+                        );
+                        String::new()
+                    }
+                    ("subfile", Some(arg)) => {
+                        let content = self.inline(&caps[0], base, arg, depth);
+                        document_body(&content).to_string()
+                    }
+                    ("bibliography", Some(_)) => match &self.bbl {
+                        Some(bbl) => match read(bbl) {
+                            Ok(content) => content,
+                            Err(_) => {
+                                self.missing.push(bbl.clone());
+                                caps[0].to_string()
+                            }
+                        },
+                        None => caps[0].to_string(),
+                    },
+                    // the style has been applied to the bbl already
+                    ("bibliographystyle", Some(_)) if self.bbl.is_some() => String::new(),
+                    _ => caps[0].to_string(),
+                }
+            });
+
+            out.push_str(&code);
+            out.push_str(comment);
+            if end_input {
+                if !out.ends_with('\n') {
+                    out.push('\n');
+                }
+                break;
+            }
+        }
+
+        out
+    }
+
+    /// Expand the file referred by `command`, or keep the command if
+    /// the file is missing
+    fn inline(&mut self, command: &str, base: &Path, name: &str, depth: usize) -> String {
+        if depth >= MAX_DEPTH {
+            warn!("Too deep to expand {}, is there a cycle?", command);
+            return command.to_string();
+        }
+        let path = match resolve(base, name) {
+            Some(path) => path,
+            None => {
+                self.missing.push(base.join(name));
+                return command.to_string();
+            }
+        };
+        let content = match read(&path) {
+            Ok(content) => content,
+            Err(_) => {
+                self.missing.push(path);
+                return command.to_string();
+            }
+        };
+        // files found in an imported directory refer to their siblings
+        let base = match command.starts_with("\\import") || command.starts_with("\\subimport") {
+            true => path.parent().unwrap_or(base).to_path_buf(),
+            false => base.to_path_buf(),
+        };
+
+        let mut expanded = self.expand_text(&content, &base, depth + 1);
+        // keep the file boundary, as TeX ends the line at the end of a file
+        if !expanded.ends_with('\n') {
+            expanded.push('\n');
+        }
+        expanded
+    }
+}
+
+/// TeX tries `name.tex` before `name`
+fn resolve(base: &Path, name: &str) -> Option<PathBuf> {
+    let path = base.join(name);
+    let mut with_tex = path.clone().into_os_string();
+    with_tex.push(".tex");
+    let with_tex = PathBuf::from(with_tex);
+
+    [with_tex, path].into_iter().find(|path| path.is_file())
+}
+
+fn read(path: &Path) -> io::Result<String> {
+    let content = fs::read(path)?;
+    Ok(String::from_utf8_lossy(&content).into_owned())
+}
+
+/// Split the line at the first `%` which is not escaped by `\`
+pub fn split_comment(line: &str) -> (&str, &str) {
+    let mut backslashes = 0;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '\\' => backslashes += 1,
+            '%' if backslashes % 2 == 0 => return line.split_at(idx),
+            _ => backslashes = 0,
+        }
+    }
+    (line, "")
+}
+
+/// The content between `\begin{document}` and `\end{document}`, used by `\subfile`
+fn document_body(content: &str) -> &str {
+    let begin = "\\begin{document}";
+    let start = match content.find(begin) {
+        Some(start) => start + begin.len(),
+        None => return content,
+    };
+    let end = content[start..]
+        .find("\\end{document}")
+        .map_or(content.len(), |end| start + end);
+    &content[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A project with `files` given as `(path, content)`, flattened from `main.tex`
+    /// with `main.bbl` if any
+    fn flatten(files: &[(&str, &str)]) -> (String, Vec<PathBuf>) {
+        let dir = TempDir::new().unwrap();
+        for (path, content) in files {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        let bbl = dir.path().join("main.bbl");
+        let bbl = Some(bbl).filter(|bbl| bbl.exists());
+        let mut flattener = Flattener::new(dir.path().to_path_buf()).bbl(bbl);
+        let content = flattener.flatten(&dir.path().join("main.tex")).unwrap();
+        let missing = flattener
+            .missing()
+            .iter()
+            .map(|path| path.strip_prefix(dir.path()).unwrap().to_path_buf())
+            .collect();
+        (content, missing)
+    }
+
+    #[test]
+    fn input_with_braces() {
+        let (content, _) = flatten(&[("main.tex", "a\\input{sec}b\n"), ("sec.tex", "S")]);
+        assert_eq!(content, "aS\nb\n");
+    }
+
+    #[test]
+    fn input_without_braces() {
+        let (content, _) = flatten(&[("main.tex", "\\input sec\nb\n"), ("sec.tex", "S\n")]);
+        assert_eq!(content, "S\n\nb\n");
+    }
+
+    #[test]
+    fn input_file_without_extension() {
+        let (content, _) = flatten(&[("main.tex", "\\input{data.txt}\n"), ("data.txt", "D\n")]);
+        assert_eq!(content, "D\n\n");
+    }
+
+    #[test]
+    fn input_keeps_following_group() {
+        let (content, _) = flatten(&[("main.tex", "\\input{a}{\\bf x}\n"), ("a.tex", "A")]);
+        assert_eq!(content, "A\n{\\bf x}\n");
+    }
+
+    #[test]
+    fn include_clears_page() {
+        let (content, _) = flatten(&[("main.tex", "\\include{ch1}{}\n"), ("ch1.tex", "C\n")]);
+        assert_eq!(content, "\\clearpage{}C\n\\clearpage{}{}\n");
+    }
+
+    #[test]
+    fn includeonly_skips_others() {
+        let (content, _) = flatten(&[
+            (
+                "main.tex",
+                "\\includeonly{ch2, ch3}\n\\include{ch1}\n\\include{ch2}\n",
+            ),
+            ("ch1.tex", "one\n"),
+            ("ch2.tex", "two\n"),
+        ]);
+        assert_eq!(
+            content,
+            "\n\\clearpage{}\n\\clearpage{}two\n\\clearpage{}\n"
+        );
+    }
+
+    #[test]
+    fn subfile_takes_document_body() {
+        let (content, _) = flatten(&[
+            ("main.tex", "\\subfile{sub/part}\n"),
+            (
+                "sub/part.tex",
+                "\\documentclass[../main]{subfiles}\n\\begin{document}\nP\n\\end{document}\n",
+            ),
+        ]);
+        assert_eq!(content, "\nP\n\n");
+    }
+
+    #[test]
+    fn import_is_relative_to_root() {
+        let (content, _) = flatten(&[
+            ("main.tex", "\\import{chap/}{intro}\n"),
+            ("chap/intro.tex", "I \\input{fig}\n"),
+            ("chap/fig.tex", "F"),
+        ]);
+        assert_eq!(content, "I F\n\n\n");
+    }
+
+    #[test]
+    fn subimport_is_relative_to_current_file() {
+        let (content, _) = flatten(&[
+            ("main.tex", "\\import{chap/}{intro}\n"),
+            ("chap/intro.tex", "\\subimport{sec/}{one}\n"),
+            ("chap/sec/one.tex", "O\n"),
+        ]);
+        assert_eq!(content, "O\n\n\n");
+    }
+
+    #[test]
+    fn import_without_file_is_untouched() {
+        let (content, missing) = flatten(&[("main.tex", "\\import{chap/}\n")]);
+        assert_eq!(content, "\\import{chap/}\n");
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn endinput_stops_after_line() {
+        let (content, _) = flatten(&[
+            ("main.tex", "\\input{sec}after\n"),
+            ("sec.tex", "S \\endinput \\input{x}\nignored\n"),
+        ]);
+        assert_eq!(content, "S  \\input{x}\nafter\n");
+    }
+
+    #[test]
+    fn commented_directives_are_kept() {
+        let (content, missing) = flatten(&[
+            ("main.tex", "% \\input{sec}\na \\% b % \\include{ch1}\n"),
+            ("sec.tex", "S\n"),
+        ]);
+        assert_eq!(content, "% \\input{sec}\na \\% b % \\include{ch1}\n");
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn missing_file_is_left_unexpanded() {
+        let (content, missing) = flatten(&[("main.tex", "\\input{nope}\n\\include{gone}\n")]);
+        assert_eq!(
+            content,
+            "\\input{nope}\n\\clearpage{}\\include{gone}\\clearpage{}\n"
+        );
+        assert_eq!(missing, vec![PathBuf::from("nope"), PathBuf::from("gone")]);
+    }
+
+    #[test]
+    fn bibliography_is_replaced_by_bbl() {
+        let (content, _) = flatten(&[
+            (
+                "main.tex",
+                "\\bibliographystyle{plain}\n\\bibliography{refs}\n",
+            ),
+            (
+                "main.bbl",
+                "\\begin{thebibliography}{1}\n\\end{thebibliography}\n",
+            ),
+        ]);
+        assert_eq!(
+            content,
+            "\n\\begin{thebibliography}{1}\n\\end{thebibliography}\n\n"
+        );
+    }
+
+    #[test]
+    fn biblatex_commands_are_kept() {
+        let tex = "\\usepackage{biblatex}\n\\addbibresource{refs.bib}\n\\printbibliography\n";
+        let (content, missing) = flatten(&[("main.tex", tex), ("main.bbl", "\\refsection{0}\n")]);
+        assert_eq!(content, tex);
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn cycle_is_cut() {
+        let (content, _) = flatten(&[("main.tex", "\\input{main}\n")]);
+        assert!(content.contains("\\input{main}"));
+    }
+}
@@ -1,8 +1,14 @@
+use crate::error::{Error, ErrorKind};
+use crate::tmp_dir;
 use crate::wrapper::CommitWrapper;
 use crate::Config;
 use git2::build::CheckoutBuilder;
-use git2::{Oid, Repository};
-use std::path::Path;
+use git2::{DiffFindOptions, Oid, Repository, Tree};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
 
 pub struct Git<'a> {
     config: &'a Config,
@@ -13,23 +19,29 @@ impl<'a> Git<'a> {
     pub fn new(config: &'a Config, repo: &'a Repository) -> Self {
         Git { config, repo }
     }
-    pub fn checkout_to<P>(&self, wrapper: CommitWrapper, target_dir: P)
+    pub fn checkout_to<P>(&self, wrapper: CommitWrapper, target_dir: P) -> Result<(), Error>
     where
         P: AsRef<Path>,
     {
         match wrapper {
+            CommitWrapper::Worktree => self.checkout_worktree_to(target_dir),
             CommitWrapper::Index => self.checkout_index_to(target_dir),
             CommitWrapper::Commit(oid) => self.checkout_commit_to(oid, target_dir),
         }
     }
 
-    fn checkout_commit_to<P>(&self, commit_id: Oid, target_dir: P)
+    fn checkout_commit_to<P>(&self, commit_id: Oid, target_dir: P) -> Result<(), Error>
     where
         P: AsRef<Path>,
     {
-        // TODO: Error Handling
-        let commit = self.repo.find_commit(commit_id).unwrap();
-        let root = commit.tree().unwrap().into_object();
+        let checkout_error = |e: git2::Error| {
+            Error::new(ErrorKind::IoError(
+                target_dir.as_ref().to_path_buf(),
+                e.message().to_string(),
+            ))
+        };
+        let commit = self.repo.find_commit(commit_id).map_err(checkout_error)?;
+        let root = commit.tree().map_err(checkout_error)?.into_object();
 
         self.repo
             .checkout_tree(
@@ -41,14 +53,28 @@ impl<'a> Git<'a> {
                         .update_index(false), // <= prevent making index messy
                 ),
             )
-            .unwrap();
+            .map_err(checkout_error)
     }
 
-    fn checkout_index_to<P>(&self, target_dir: P)
+    /// Copy the working directory to `target_dir`, files ignored by `.gitignore`
+    /// are skipped unless tracked, while untracked ones are kept
+    fn checkout_worktree_to<P>(&self, target_dir: P) -> Result<(), Error>
     where
         P: AsRef<Path>,
     {
-        // TODO: Error Handling
+        copy_worktree(self.repo, &self.config.tmp_dir, target_dir.as_ref())
+    }
+
+    fn checkout_index_to<P>(&self, target_dir: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let checkout_error = |e: git2::Error| {
+            Error::new(ErrorKind::IoError(
+                target_dir.as_ref().to_path_buf(),
+                e.message().to_string(),
+            ))
+        };
         self.repo
             .checkout_index(
                 None,
@@ -59,6 +85,193 @@ impl<'a> Git<'a> {
                         .update_index(false), // <= prevent making index messy
                 ),
             )
-            .unwrap();
+            .map_err(checkout_error)
+    }
+
+    fn tree_of(&self, wrapper: CommitWrapper) -> Option<Tree<'_>> {
+        let oid = match wrapper {
+            // untracked files are not in any tree
+            CommitWrapper::Worktree => return None,
+            CommitWrapper::Index => self.repo.index().ok()?.write_tree().ok()?,
+            CommitWrapper::Commit(oid) => self.repo.find_commit(oid).ok()?.tree_id(),
+        };
+        self.repo.find_tree(oid).ok()
+    }
+
+    /// Id of the tree of `wrapper`, `None` for the working tree
+    pub fn tree_id(&self, wrapper: CommitWrapper) -> Option<Oid> {
+        self.tree_of(wrapper).map(|tree| tree.id())
+    }
+
+    /// Files renamed between two versions, in `(old path, new path)` pairs
+    pub fn renames(&self, old: CommitWrapper, new: CommitWrapper) -> Vec<(PathBuf, PathBuf)> {
+        let (old, new) = match (self.tree_of(old), self.tree_of(new)) {
+            (Some(old), Some(new)) => (old, new),
+            _ => return Vec::new(),
+        };
+        let mut diff = match self.repo.diff_tree_to_tree(Some(&old), Some(&new), None) {
+            Ok(diff) => diff,
+            Err(_) => return Vec::new(),
+        };
+        if diff
+            .find_similar(Some(DiffFindOptions::new().renames(true)))
+            .is_err()
+        {
+            return Vec::new();
+        }
+
+        diff.deltas()
+            .filter(|delta| delta.status() == git2::Delta::Renamed)
+            .filter_map(|delta| {
+                let old = delta.old_file().path()?.to_path_buf();
+                let new = delta.new_file().path()?.to_path_buf();
+                Some((old, new))
+            })
+            .collect()
+    }
+}
+
+/// Copy the working directory of `repo` to `target_dir`, skipping `tmp_dir`
+/// and every tmp dir placed aside it
+fn copy_worktree(repo: &Repository, tmp_dir: &Path, target_dir: &Path) -> Result<(), Error> {
+    let workdir = repo.workdir().ok_or_else(|| {
+        Error::new(ErrorKind::IoError(
+            repo.path().to_path_buf(),
+            String::from("a bare repo has no working tree"),
+        ))
+    })?;
+    let index = repo.index().map_err(|e| {
+        Error::new(ErrorKind::IoError(
+            repo.path().join("index"),
+            e.message().to_string(),
+        ))
+    })?;
+    // a tracked file is always copied, even if it matches `.gitignore`
+    let tracked: HashSet<PathBuf> = index
+        .iter()
+        .filter_map(|entry| String::from_utf8(entry.path).ok())
+        .map(PathBuf::from)
+        .collect();
+    let ignored = |relative: &Path| repo.is_path_ignored(relative).unwrap_or(false);
+
+    let tmp_parent = tmp_dir.parent().unwrap_or(Path::new(""));
+    let walker = WalkDir::new(workdir).into_iter().filter_entry(|dent| {
+        let path = dent.path();
+        let relative = path.strip_prefix(workdir).unwrap();
+        // never copy the tmp dir into itself, nor those left by other runs
+        if path.starts_with(tmp_dir)
+            || path.starts_with(target_dir)
+            || tmp_dir::is_tmp_dir(path, tmp_parent)
+            || relative.starts_with(".git")
+        {
+            return false;
+        }
+        // an ignored dir is only entered when it holds tracked files
+        !(dent.file_type().is_dir()
+            && ignored(relative)
+            && !tracked.iter().any(|file| file.starts_with(relative)))
+    });
+
+    for dent in walker {
+        let dent = dent.map_err(|e| {
+            let path = e.path().unwrap_or(workdir).to_path_buf();
+            Error::new(ErrorKind::IoError(path, e.to_string()))
+        })?;
+        let relative = dent.path().strip_prefix(workdir).unwrap();
+        if dent.file_type().is_dir() {
+            continue; // git does not track empty dirs either
+        }
+        if !tracked.contains(relative) && ignored(relative) {
+            continue;
+        }
+        let target = target_dir.join(relative);
+        fs::create_dir_all(target.parent().unwrap())
+            .and_then(|_| match dent.path_is_symlink() {
+                true => copy_symlink(dent.path(), &target),
+                false => fs::copy(dent.path(), &target).map(|_| ()),
+            })
+            .map_err(|e| {
+                Error::new(ErrorKind::IoError(dent.path().to_path_buf(), e.to_string()))
+            })?;
+    }
+    Ok(())
+}
+
+/// Recreate the symlink `src` at `target`, as `fs::copy` fails on a link to a dir
+#[cfg(unix)]
+fn copy_symlink(src: &Path, target: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(src)?, target)
+}
+
+/// Recreate the symlink `src` at `target`, as `fs::copy` fails on a link to a dir
+#[cfg(windows)]
+fn copy_symlink(src: &Path, target: &Path) -> io::Result<()> {
+    let link = fs::read_link(src)?;
+    match fs::metadata(src)?.is_dir() {
+        true => std::os::windows::fs::symlink_dir(link, target),
+        false => std::os::windows::fs::symlink_file(link, target),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write(root: &Path, relative: &str, content: &str) {
+        let path = root.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn copy_worktree_keeps_tracked_and_untracked_files() {
+        let workdir = TempDir::new().unwrap();
+        let repo = Repository::init(workdir.path()).unwrap();
+        write(workdir.path(), ".gitignore", "*.pdf\nbuild/\n");
+        write(workdir.path(), "main.tex", "tracked");
+        write(workdir.path(), "figure.pdf", "tracked but ignored");
+        write(
+            workdir.path(),
+            "build/style.sty",
+            "tracked in an ignored dir",
+        );
+        write(workdir.path(), "build/main.aux", "ignored");
+        write(workdir.path(), "main.pdf", "ignored");
+        write(workdir.path(), "chapter.tex", "untracked");
+        let mut index = repo.index().unwrap();
+        for file in ["main.tex", "figure.pdf", "build/style.sty"] {
+            index.add_path(Path::new(file)).unwrap();
+        }
+        index.write().unwrap();
+
+        let target = TempDir::new().unwrap();
+        let tmp_dir = workdir.path().join("build/tmp");
+        copy_worktree(&repo, &tmp_dir, target.path()).unwrap();
+
+        for file in ["main.tex", "figure.pdf", "build/style.sty", "chapter.tex"] {
+            assert!(target.path().join(file).is_file(), "{} is missing", file);
+        }
+        for file in ["build/main.aux", "main.pdf"] {
+            assert!(!target.path().join(file).exists(), "{} is copied", file);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_worktree_recreates_symlinked_dirs() {
+        let workdir = TempDir::new().unwrap();
+        let repo = Repository::init(workdir.path()).unwrap();
+        write(workdir.path(), "figures/plot.tex", "plot");
+        std::os::unix::fs::symlink("figures", workdir.path().join("images")).unwrap();
+
+        let target = TempDir::new().unwrap();
+        let tmp_dir = workdir.path().join("build/tmp");
+        copy_worktree(&repo, &tmp_dir, target.path()).unwrap();
+
+        let link = target.path().join("images");
+        assert!(link.is_symlink());
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("figures"));
+        assert!(link.join("plot.tex").is_file());
     }
 }
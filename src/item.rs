@@ -1,10 +1,14 @@
+use crate::wrapper::CommitWrapper;
 use crossterm::style::Stylize;
-use git2::{Oid, Repository};
+use git2::{Repository, Status, StatusOptions};
+use std::sync::Arc;
+
+#[cfg(all(feature = "skim", not(windows)))]
 use skim::prelude::*;
 
 pub struct Item {
     pub repo: Arc<Repository>,
-    pub oid: Oid,
+    pub wrapper: CommitWrapper,
 }
 
 // See: https://github.com/rust-lang/git2-rs/issues/194
@@ -13,40 +17,113 @@ unsafe impl Sync for Item {}
 // See: https://github.com/rust-lang/git2-rs/issues/194
 unsafe impl Send for Item {}
 
+impl Item {
+    /// Changes that are not committed yet, in the form of `git status --short`
+    fn status(&self, staged: bool) -> String {
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(!staged).recurse_untracked_dirs(true);
+        let statuses = match self.repo.statuses(Some(&mut opts)) {
+            Ok(statuses) => statuses,
+            Err(e) => return format!("Can not read the status: {}", e.message()),
+        };
+
+        let mut lines = Vec::new();
+        for entry in statuses.iter() {
+            let status = entry.status();
+            let flag = match staged {
+                true if status.is_index_new() => "A",
+                true if status.is_index_modified() => "M",
+                true if status.is_index_deleted() => "D",
+                true if status.is_index_renamed() => "R",
+                true => continue,
+                false if status.contains(Status::WT_NEW) => "??",
+                false if status.is_wt_modified() || status.is_index_modified() => "M",
+                false if status.is_wt_deleted() || status.is_index_deleted() => "D",
+                false if status.is_index_new() => "A",
+                false if status.is_wt_renamed() || status.is_index_renamed() => "R",
+                false => continue,
+            };
+            lines.push(format!("{:>2} {}", flag, entry.path().unwrap_or_default()));
+        }
+        match lines.is_empty() {
+            true => String::from("Nothing changed"),
+            false => lines.join("\n"),
+        }
+    }
+
+    /// The line shown in the list
+    pub fn line(&self) -> String {
+        match self.wrapper {
+            CommitWrapper::Worktree => String::from("worktree Uncommitted and untracked changes"),
+            CommitWrapper::Index => String::from("index   Staged changes"),
+            CommitWrapper::Commit(oid) => {
+                let summary = self.repo.find_commit(oid).map_or_else(
+                    |e| format!("Can not read the commit: {}", e.message()),
+                    |commit| commit.summary().unwrap_or_default().to_string(),
+                );
+                let oid = format!("{}", oid);
+                format!("{} {}", &oid[0..7], summary)
+            }
+        }
+    }
+
+    pub fn preview(&self) -> String {
+        match self.wrapper {
+            CommitWrapper::Worktree => {
+                format!("{}\n\n{}", "Working tree".yellow(), self.status(false))
+            }
+            CommitWrapper::Index => format!("{}\n\n{}", "Index".yellow(), self.status(true)),
+            CommitWrapper::Commit(oid) => {
+                let commit = match self.repo.find_commit(oid) {
+                    Ok(commit) => commit,
+                    Err(e) => return format!("Can not read the commit: {}", e.message()),
+                };
+                let oid = format!("{}", commit.id());
+                format!(
+                    "commit {}\nAuthor: {}\n\n{}",
+                    oid.yellow(),
+                    commit.author(),
+                    String::from_utf8_lossy(commit.message_bytes())
+                )
+            }
+        }
+    }
+
+    /// Message shown once the item is selected
+    pub fn output(&self) -> String {
+        match self.wrapper {
+            CommitWrapper::Worktree => String::from("Working tree has been selected."),
+            CommitWrapper::Index => String::from("Index has been selected."),
+            CommitWrapper::Commit(_) => format!("{} has been selected.", self.line()),
+        }
+    }
+}
+
+#[cfg(all(feature = "skim", not(windows)))]
 impl SkimItem for Item {
-    fn text(&self) -> Cow<str> {
-        let commit = self.repo.find_commit(self.oid).unwrap();
-        let message = commit.message().unwrap();
-        let oid = format!("{}", commit.id());
-        return Cow::from(String::from(format!("{} {}", oid, message)));
+    fn text(&self) -> Cow<'_, str> {
+        match self.wrapper {
+            CommitWrapper::Worktree => Cow::from("worktree working tree"),
+            CommitWrapper::Index => Cow::from("index staged"),
+            CommitWrapper::Commit(oid) => {
+                let message = self.repo.find_commit(oid).map_or_else(
+                    |_| String::new(),
+                    |commit| String::from_utf8_lossy(commit.message_bytes()).into_owned(),
+                );
+                Cow::from(format!("{} {}", oid, message))
+            }
+        }
     }
 
     fn display<'a>(&'a self, _context: DisplayContext<'a>) -> AnsiString<'a> {
-        let commit = self.repo.find_commit(self.oid).unwrap();
-        let summary = commit.summary().unwrap();
-        let oid = format!("{}", commit.id());
-        return AnsiString::from(String::from(format!("{} {}", &oid[0..7], summary)));
+        AnsiString::from(self.line())
     }
 
     fn preview(&self, _context: PreviewContext) -> ItemPreview {
-        let commit = self.repo.find_commit(self.oid).unwrap();
-        let oid = format!("{}", commit.id());
-        ItemPreview::AnsiText(format!(
-            "commit {}\nAuthor: {}\n\n{}",
-            oid.yellow(),
-            commit.author().to_string(),
-            commit.message().unwrap()
-        ))
-    }
-
-    fn output(&self) -> Cow<str> {
-        let commit = self.repo.find_commit(self.oid).unwrap();
-        let summary = commit.summary().unwrap();
-        let oid = format!("{}", commit.id());
-        return Cow::from(String::from(format!(
-            "{} {} has been selected.",
-            &oid[0..7],
-            summary
-        )));
+        ItemPreview::AnsiText(Item::preview(self))
+    }
+
+    fn output(&self) -> Cow<'_, str> {
+        Cow::from(Item::output(self))
     }
 }
@@ -1,17 +1,32 @@
 use crossterm::style::Stylize;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
-use crate::config;
 use crate::error::{Error, ErrorKind};
+use crate::flatten::{split_comment, Flattener};
+use crate::progress::{Progress, ProgressEvent};
+use crate::texlog;
+use crate::texlog::{Diagnostic, DiagnosticKind};
+use crate::tmp_dir;
+use crate::tools::{ToolName, Tools};
 use clap::ValueEnum;
 use grep::regex::RegexMatcher;
 use grep::searcher::sinks::UTF8;
 use grep::searcher::{BinaryDetection, SearcherBuilder};
+use regex::Regex;
+use serde::Deserialize;
 use walkdir::WalkDir;
 
 pub struct LaTeX {
@@ -21,13 +36,52 @@ pub struct LaTeX {
 pub struct Config {
     pub project_dir: PathBuf,
     pub main_tex: PathBuf,
+    pub engine: Engine,
+    pub bib_backend: BibBackend,
+    pub expander: Expander,
+    pub max_passes: usize,
+    /// Which version is being built, e.g. old, new or diff
+    pub version: String,
+    pub capture: Capture,
+    /// Directory to place the output of tools in [`Capture::File`] mode
+    pub log_dir: PathBuf,
     pub abort_if_error: bool,
+    /// Set to stop the build, e.g. when the other version fails
+    pub cancel: Arc<AtomicBool>,
+    pub tools: Tools,
+    pub progress: Progress,
+    /// Tools that have failed without aborting the build, shared with other builds
+    pub failures: Arc<Mutex<Vec<String>>>,
+    /// Extra args of the engine, placed before the main tex
+    pub engine_args: Vec<OsString>,
+    /// Extra args of bibtex or biber
+    pub bib_args: Vec<OsString>,
+    /// Extra args of latexpand
+    pub expand_args: Vec<OsString>,
+    /// Extra args of latexdiff
+    pub latexdiff_args: Vec<OsString>,
 }
 
 pub struct ConfigBuilder {
     project_dir: PathBuf,
     main_tex: Option<PathBuf>,
+    engine: Engine,
+    bib_backend: BibBackend,
+    expander: Expander,
+    max_passes: usize,
+    version: String,
+    capture: Capture,
+    log_dir: Option<PathBuf>,
     abort_if_error: bool,
+    cancel: Arc<AtomicBool>,
+    tools: Tools,
+    progress: Progress,
+    failures: Arc<Mutex<Vec<String>>>,
+    engine_args: Vec<OsString>,
+    bib_args: Vec<OsString>,
+    expand_args: Vec<OsString>,
+    latexdiff_args: Vec<OsString>,
+    tmp_parent: Option<PathBuf>,
 }
 
 impl Default for ConfigBuilder {
@@ -35,7 +89,23 @@ impl Default for ConfigBuilder {
         ConfigBuilder {
             project_dir: std::env::current_dir().unwrap(),
             main_tex: None,
+            engine: Engine::default(),
+            bib_backend: BibBackend::default(),
+            expander: Expander::default(),
+            max_passes: 5,
+            version: String::from("new"),
+            capture: Capture::default(),
+            log_dir: None,
             abort_if_error: false,
+            cancel: Arc::new(AtomicBool::new(false)),
+            tools: Tools::default(),
+            progress: Progress::default(),
+            failures: Arc::new(Mutex::new(Vec::new())),
+            engine_args: Vec::new(),
+            bib_args: Vec::new(),
+            expand_args: Vec::new(),
+            latexdiff_args: Vec::new(),
+            tmp_parent: None,
         }
     }
 }
@@ -55,45 +125,323 @@ impl ConfigBuilder {
         self
     }
 
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    pub fn bib_backend(mut self, backend: BibBackend) -> Self {
+        self.bib_backend = backend;
+        self
+    }
+
+    pub fn expander(mut self, expander: Expander) -> Self {
+        self.expander = expander;
+        self
+    }
+
+    /// Upper bound of the passes in [`LaTeX::compile_until_stable`]
+    pub fn max_passes(mut self, passes: usize) -> Self {
+        self.max_passes = passes;
+        self
+    }
+
+    pub fn version(mut self, version: &str) -> Self {
+        self.version = String::from(version);
+        self
+    }
+
+    pub fn capture(mut self, capture: Capture) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// Directory to place the output of tools, `<project_dir>/logs` by default
+    pub fn log_dir(mut self, dir: PathBuf) -> Self {
+        self.log_dir = Some(dir);
+        self
+    }
+
     pub fn abort_if_error(mut self, on: bool) -> Self {
         self.abort_if_error = on;
         self
     }
 
+    /// Share a flag with other builds, the running tool is killed once it is set
+    pub fn cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn tools(mut self, tools: Tools) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    /// Where to report the progress of the build
+    pub fn progress(mut self, progress: Progress) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Record the tools that fail without aborting the build in `failures`
+    pub fn failures(mut self, failures: Arc<Mutex<Vec<String>>>) -> Self {
+        self.failures = failures;
+        self
+    }
+
+    pub fn engine_args(mut self, args: Vec<OsString>) -> Self {
+        self.engine_args = args;
+        self
+    }
+
+    pub fn bib_args(mut self, args: Vec<OsString>) -> Self {
+        self.bib_args = args;
+        self
+    }
+
+    /// Only taken by [`Expander::Latexpand`]
+    pub fn expand_args(mut self, args: Vec<OsString>) -> Self {
+        self.expand_args = args;
+        self
+    }
+
+    pub fn latexdiff_args(mut self, args: Vec<OsString>) -> Self {
+        self.latexdiff_args = args;
+        self
+    }
+
+    /// Directory in the project holding the tmp dirs of ours, which are
+    /// skipped when searching for the main TeX
+    pub fn tmp_parent(mut self, dir: Option<PathBuf>) -> Self {
+        self.tmp_parent = dir;
+        self
+    }
+
     fn guess_main_tex(&self) -> std::result::Result<PathBuf, Error> {
-        if self.main_tex.is_some() {
-            return Ok(self.main_tex.clone().unwrap());
+        if let Some(main_tex) = &self.main_tex {
+            return match main_tex.is_file() {
+                true => Ok(main_tex.clone()),
+                false => {
+                    error!("Main TeX {} does not exist", main_tex.display());
+                    Err(Error::new(ErrorKind::MainTeXNotFound))
+                }
+            };
         }
 
         warn!("Main TeX file is not given");
-        let mut matches = ConfigBuilder::main_searcher(&self.project_dir);
-        return match matches.len() {
-            0 => {
+        if let Some(main_tex) = ConfigBuilder::latexmkrc_default(&self.project_dir) {
+            info!("latexmkrc says main TeX is {}", main_tex.display());
+            return Ok(main_tex);
+        }
+        if let Some(main_tex) = self.latexmain_marker(&self.project_dir) {
+            info!(".latexmain marks main TeX as {}", main_tex.display());
+            return Ok(main_tex);
+        }
+        if let Some(main_tex) = self.magic_root(&self.project_dir) {
+            info!("Magic comment says main TeX is {}", main_tex.display());
+            return Ok(main_tex);
+        }
+
+        let matches = self.main_searcher(&self.project_dir);
+        let ranked = ConfigBuilder::rank(&self.project_dir, matches);
+        for (candidate, score) in &ranked {
+            debug!("Candidate {} scores {}", candidate.display(), score);
+        }
+        match ranked.as_slice() {
+            [] => {
                 warn!("Searcher can't guess the Main TeX file");
                 Err(Error::new(ErrorKind::MainTeXNotFound))
             }
-            _ => {
-                let guess = matches.pop().unwrap();
+            [(_, best), (_, second), ..] if best == second => {
+                warn!("Searcher can't tell the Main TeX file from others");
+                let candidates = ranked
+                    .iter()
+                    .filter(|(_, score)| score == best)
+                    .map(|(candidate, _)| {
+                        // relative path can be passed to `--main-tex` directly
+                        let relative = candidate.strip_prefix(&self.project_dir);
+                        relative.unwrap_or(candidate).to_path_buf()
+                    })
+                    .collect();
+                Err(Error::new(ErrorKind::AmbiguousMainTeX(candidates)))
+            }
+            [(guess, _), ..] => {
                 info!("Searcher guess main TeX is {}", &guess.display());
-                Ok(guess)
+                Ok(guess.clone())
             }
-        };
+        }
+    }
+
+    /// Score the candidates of main TeX, the most likely one comes first
+    fn rank(root: &Path, candidates: Vec<PathBuf>) -> Vec<(PathBuf, i32)> {
+        const MAIN_NAMES: [&str; 8] = [
+            "main",
+            "thesis",
+            "paper",
+            "article",
+            "report",
+            "manuscript",
+            "document",
+            "ms",
+        ];
+        const ASSET_DIRS: [&str; 6] = ["figures", "figure", "figs", "fig", "images", "img"];
+        let includes = Regex::new(r"\\(input|include|subfile|import|subimport)\b").unwrap();
+        let standalone = Regex::new(r"\\documentclass\s*(\[[^\]]*\])?\s*\{standalone\}").unwrap();
+
+        let mut ranked = Vec::<(PathBuf, i32)>::new();
+        for candidate in candidates {
+            if ranked.iter().any(|(path, _)| path == &candidate) {
+                continue;
+            }
+            let content = match fs::read(&candidate) {
+                Ok(content) => String::from_utf8_lossy(&content).into_owned(),
+                Err(_) => continue,
+            };
+            let relative = candidate.strip_prefix(root).unwrap_or(&candidate);
+            let depth = relative.components().count() as i32 - 1;
+
+            let mut score = 0;
+            // top-level files are more likely to be the main one
+            score -= depth;
+            if content.contains("\\begin{document}") {
+                score += 3;
+            }
+            if includes.is_match(&content) {
+                score += 2;
+            }
+            let stem = candidate.file_stem().unwrap_or_default().to_string_lossy();
+            if MAIN_NAMES.contains(&stem.to_lowercase().as_str()) {
+                score += 2;
+            }
+            if standalone.is_match(&content) {
+                score -= 3;
+            }
+            if relative
+                .parent()
+                .into_iter()
+                .flat_map(|dir| dir.components())
+                .any(|dir| ASSET_DIRS.contains(&dir.as_os_str().to_string_lossy().as_ref()))
+            {
+                score -= 4;
+            }
+            ranked.push((candidate, score));
+        }
+
+        ranked.sort_by(|(_, a), (_, b)| b.cmp(a));
+        ranked
     }
 
     pub fn build(self) -> std::result::Result<Config, Error> {
         let main_tex = self.guess_main_tex()?;
+        let log_dir = self
+            .log_dir
+            .unwrap_or_else(|| self.project_dir.join("logs"));
+        // the main TeX may live in a sub directory, and `\input`s are relative to it
+        let project_dir = main_tex.parent().unwrap().to_path_buf();
         Ok(Config {
-            project_dir: self.project_dir,
+            project_dir,
             main_tex,
+            engine: self.engine,
+            bib_backend: self.bib_backend,
+            expander: self.expander,
+            max_passes: self.max_passes,
+            version: self.version,
+            capture: self.capture,
+            log_dir,
             abort_if_error: self.abort_if_error,
+            cancel: self.cancel,
+            tools: self.tools,
+            progress: self.progress,
+            failures: self.failures,
+            engine_args: self.engine_args,
+            bib_args: self.bib_args,
+            expand_args: self.expand_args,
+            latexdiff_args: self.latexdiff_args,
         })
     }
 
-    fn main_searcher(path: &PathBuf) -> Vec<PathBuf> {
+    /// `@default_files = ('main.tex');` in `latexmkrc` or `.latexmkrc`
+    fn latexmkrc_default(path: &Path) -> Option<PathBuf> {
+        let pattern = Regex::new(r#"@default_files\s*=\s*\(\s*['"]([^'"]+)['"]"#).unwrap();
+        ["latexmkrc", ".latexmkrc"]
+            .iter()
+            .filter_map(|rc| fs::read_to_string(path.join(rc)).ok())
+            .filter_map(|rc| {
+                let caps = pattern.captures(&rc)?;
+                let mut main_tex = path.join(&caps[1]);
+                if main_tex.extension().is_none() {
+                    main_tex.set_extension("tex");
+                }
+                Some(main_tex)
+            })
+            .find(|main_tex| main_tex.is_file())
+    }
+
+    /// Whether `path` is one of the tmp dirs of ours in the project
+    fn is_tmp_dir(&self, path: &Path) -> bool {
+        match &self.tmp_parent {
+            Some(parent) => tmp_dir::is_tmp_dir(path, parent),
+            None => false,
+        }
+    }
+
+    /// Files under `path`, skipping the tmp dirs of ours
+    fn walk<'a>(&'a self, path: &Path) -> impl Iterator<Item = walkdir::DirEntry> + 'a {
+        WalkDir::new(path)
+            .into_iter()
+            .filter_entry(|dent| !self.is_tmp_dir(dent.path()))
+            .filter_map(|dent| dent.ok())
+    }
+
+    /// `main.tex.latexmain` is an empty file marks `main.tex` as the main TeX
+    fn latexmain_marker(&self, path: &Path) -> Option<PathBuf> {
+        self.walk(path)
+            .filter(|dent| dent.path().extension() == Some(OsStr::new("latexmain")))
+            .map(|dent| dent.path().with_extension(""))
+            .find(|main_tex| main_tex.is_file())
+    }
+
+    /// `% !TEX root = ../main.tex` in the leading lines of any TeX file,
+    /// the root referred by the most files wins
+    fn magic_root(&self, path: &Path) -> Option<PathBuf> {
+        let pattern = Regex::new(r"(?i)^\s*%\s*!\s*TEX\s+root\s*=\s*(.+?)\s*$").unwrap();
+        let mut roots = Vec::<(PathBuf, usize)>::new();
+
+        for dent in self.walk(path) {
+            if !dent.file_type().is_file() || dent.path().extension() != Some(OsStr::new("tex")) {
+                continue;
+            }
+            let content = match fs::read(dent.path()) {
+                Ok(content) => String::from_utf8_lossy(&content).into_owned(),
+                Err(_) => continue,
+            };
+            let root = content
+                .lines()
+                .take(20)
+                .find_map(|line| pattern.captures(line))
+                .map(|caps| dent.path().parent().unwrap().join(&caps[1]));
+            let root = match root.and_then(|root| fs::canonicalize(root).ok()) {
+                Some(root) if root.is_file() => root,
+                _ => continue,
+            };
+            match roots.iter_mut().find(|(path, _)| path == &root) {
+                Some((_, count)) => *count += 1,
+                None => roots.push((root, 1)),
+            }
+        }
+
+        roots
+            .into_iter()
+            .max_by_key(|(_, count)| *count)
+            .map(|(root, _)| root)
+    }
+
+    fn main_searcher(&self, path: &PathBuf) -> Vec<PathBuf> {
         // See https://github.com/BurntSushi/ripgrep/blob/master/crates/grep/examples/simplegrep.rs
         // See https://docs.rs/grep-searcher/0.1.11/grep_searcher/index.html
         let pattern = r"\\documentclass";
-        let matcher = RegexMatcher::new_line_matcher(&pattern).unwrap();
+        let matcher = RegexMatcher::new_line_matcher(pattern).unwrap();
         let mut searcher = SearcherBuilder::new()
             .binary_detection(BinaryDetection::quit(b'\x00'))
             .line_number(true)
@@ -101,7 +449,10 @@ impl ConfigBuilder {
 
         let mut matches = Vec::<PathBuf>::new();
 
-        for result in WalkDir::new(path) {
+        for result in WalkDir::new(path)
+            .into_iter()
+            .filter_entry(|dent| !self.is_tmp_dir(dent.path()))
+        {
             let dent = match result {
                 Ok(dent) => dent,
                 Err(err) => {
@@ -145,55 +496,365 @@ impl LaTeX {
             let path = path.as_ref().unwrap().path();
             if path.extension().unwrap_or_else(|| OsStr::new("")) == ext {
                 // TODO: Enable me in verbose mode
-                info!("Found .{}: {}", ext, path.display());
+                info!("{}Found .{}: {}", self.tag(), ext, path.display());
                 res.push(path);
             }
         }
         if res.is_empty() {
-            info!(".{} Not Found!", ext);
+            info!("{}.{} Not Found!", self.tag(), ext);
+        }
+        res
+    }
+
+    fn compile_error(&self, stage: &str, diagnostics: Vec<Diagnostic>) -> Error {
+        Error::new(ErrorKind::CompileError {
+            stage: String::from(stage),
+            version: self.config.version.clone(),
+            diagnostics,
+        })
+    }
+
+    /// Prefix of the messages, which tells the versions apart when they are built in parallel
+    fn tag(&self) -> String {
+        format!("[{}] ", self.config.version)
+            .dark_grey()
+            .to_string()
+    }
+
+    /// Run the command of `stage` and wait for it to finish,
+    /// the output of the child goes wherever the capture mode says
+    fn execute(&self, stage: &str, command: &mut Command) -> Result<ExitStatus, Error> {
+        self.execute_capturing(stage, command, true)
+    }
+
+    /// Same as [`LaTeX::execute`], but the stdout of the child is left alone
+    /// unless `stdout` is set, e.g. latexdiff writes the diff result to it
+    fn execute_capturing(
+        &self,
+        stage: &str,
+        command: &mut Command,
+        stdout: bool,
+    ) -> Result<ExitStatus, Error> {
+        if self.config.cancel.load(Ordering::SeqCst) {
+            return Err(Error::new(ErrorKind::Cancelled));
+        }
+        self.config.progress.emit(ProgressEvent::ToolInvoked {
+            version: self.config.version.clone(),
+            tool: String::from(stage),
+        });
+        let ecode = self.spawn_and_wait(stage, command, stdout).map_err(|e| {
+            error!("{}Fail to run {}: {}", self.tag(), stage, e);
+            io_error(command, e)
+        })?;
+        match self.config.cancel.load(Ordering::SeqCst) {
+            true => {
+                warn!("{}{} is cancelled", self.tag(), stage);
+                Err(Error::new(ErrorKind::Cancelled))
+            }
+            false => Ok(ecode),
+        }
+    }
+
+    /// Spawn `command` and wait for it, `stdout` tells whether the stdout
+    /// of the child should be captured as well as the stderr
+    fn spawn_and_wait(
+        &self,
+        stage: &str,
+        command: &mut Command,
+        stdout: bool,
+    ) -> io::Result<ExitStatus> {
+        let (version, cancel) = (&self.config.version, &self.config.cancel);
+        match self.config.capture {
+            Capture::Quiet => {
+                if stdout {
+                    command.stdout(Stdio::null());
+                }
+                command.stderr(Stdio::null());
+                wait(&mut spawn(command)?, cancel)
+            }
+            Capture::File => {
+                let log_dir = &self.config.log_dir;
+                fs::create_dir_all(log_dir)?;
+                let log = log_dir.join(format!("{}-{}.log", version, stage));
+                debug!("Output of {} goes to {}", stage, log.display());
+                // append, since a stage may run several times
+                let file = OpenOptions::new().create(true).append(true).open(log)?;
+                if stdout {
+                    command.stdout(file.try_clone()?);
+                }
+                command.stderr(file);
+                wait(&mut spawn(command)?, cancel)
+            }
+            Capture::Stream => {
+                if stdout {
+                    command.stdout(Stdio::piped());
+                }
+                command.stderr(Stdio::piped());
+                let mut child = spawn(command)?;
+                let prefix = format!("[{}/{}]", version, stage);
+                let child_stdout = child.stdout.take();
+                let child_stderr = child.stderr.take();
+                thread::scope(|s| {
+                    if let Some(out) = child_stdout {
+                        s.spawn(|| forward(out, &prefix, false));
+                    }
+                    if let Some(err) = child_stderr {
+                        s.spawn(|| forward(err, &prefix, true));
+                    }
+                    wait(&mut child, cancel)
+                })
+            }
+        }
+    }
+
+    /// Tell how the tool of `stage` went, a failure aborts the build
+    /// with the errors in `diagnostics` if configured to, or is tolerated
+    fn check(
+        &self,
+        stage: &str,
+        ecode: ExitStatus,
+        diagnostics: Vec<Diagnostic>,
+    ) -> Result<(), Error> {
+        match (ecode.success(), self.config.abort_if_error) {
+            (true, _) => {
+                info!(
+                    "{}{}",
+                    self.tag(),
+                    "Compilation SUCCESS".green().bold().underlined()
+                );
+                Ok(())
+            }
+            (false, false) => {
+                self.tolerate(stage);
+                Ok(())
+            }
+            (false, true) => {
+                error!(
+                    "{}{}",
+                    self.tag(),
+                    "Compilation FAIL".red().bold().underlined()
+                );
+                Err(self.compile_error(stage, diagnostics))
+            }
+        }
+    }
+
+    /// The tool has failed, but the build keeps going
+    fn tolerate(&self, stage: &str) {
+        warn!(
+            "{}{}",
+            self.tag(),
+            "Compilation FAIL".yellow().bold().underlined()
+        );
+        self.config
+            .failures
+            .lock()
+            .unwrap()
+            .push(format!("{}/{}", self.config.version, stage));
+    }
+
+    /// Path of the file with extension `ext` that the engine generates
+    /// for the main tex, e.g. `main.aux` for `main.tex`
+    fn output_file(&self, ext: &str) -> PathBuf {
+        self.output_file_of(&self.config.main_tex, ext)
+    }
+
+    fn output_file_of(&self, file: &Path, ext: &str) -> PathBuf {
+        let mut path = self.config.project_dir.clone();
+        path.push(file.file_name().unwrap());
+        path.set_extension(ext);
+        path
+    }
+
+    /// Show the diagnostics in the log of `file`, return the errors among them
+    fn report(&self, file: &Path) -> Vec<Diagnostic> {
+        let diagnostics = texlog::parse_file(self.output_file_of(file, "log"));
+        let count = |kind: DiagnosticKind| diagnostics.iter().filter(|d| d.kind == kind).count();
+        info!(
+            "{}{} error(s), {} warning(s), {} undefined reference(s), {} undefined citation(s), {} bad box(es)",
+            self.tag(),
+            count(DiagnosticKind::Error),
+            count(DiagnosticKind::Warning),
+            count(DiagnosticKind::UndefinedReference),
+            count(DiagnosticKind::UndefinedCitation),
+            count(DiagnosticKind::BadBox),
+        );
+        for diagnostic in &diagnostics {
+            match diagnostic.kind {
+                DiagnosticKind::Error => error!("{}{}", self.tag(), diagnostic),
+                DiagnosticKind::BadBox => debug!("{}", diagnostic),
+                _ => warn!("{}{}", self.tag(), diagnostic),
+            }
+        }
+        self.config.progress.emit(ProgressEvent::Diagnostics {
+            version: self.config.version.clone(),
+            diagnostics: diagnostics.clone(),
+        });
+        diagnostics.into_iter().filter(|d| d.is_error()).collect()
+    }
+
+    /// Infer the bibliography backend from the files generated by the engine,
+    /// `None` if the document has no bibliography at all
+    fn detect_bib_backend(&self) -> Option<BibBackend> {
+        // biblatex always writes a control file for biber
+        if self.output_file("bcf").exists() {
+            return Some(BibBackend::Biber);
+        }
+        let aux = fs::read_to_string(self.output_file("aux")).unwrap_or_default();
+        if aux.contains("\\bibdata") {
+            return Some(BibBackend::Bibtex);
+        }
+        // the aux file may be missing if the compilation failed, look at the source instead
+        let tex = fs::read_to_string(&self.config.main_tex).unwrap_or_default();
+        bib_backend_of(&tex)
+    }
+
+    /// The configured bibliography backend, or the inferred one if it is
+    /// [`BibBackend::Auto`], `None` if the document has no bibliography at all
+    pub fn bib_backend(&self) -> Option<BibBackend> {
+        match self.config.bib_backend {
+            BibBackend::Auto => self.detect_bib_backend(),
+            backend => Some(backend),
         }
-        return res;
     }
 
+    /// Run bibtex or biber according to the configured backend,
+    /// and make sure the `.bbl` file is produced
+    pub fn bibliography(&self) -> std::result::Result<&Self, Error> {
+        let backend = match self.bib_backend() {
+            Some(backend) => backend,
+            None => {
+                info!(
+                    "{}No bibliography found, skip the bibliography stage",
+                    self.tag()
+                );
+                return Ok(self);
+            }
+        };
+
+        let aux = self.output_file("aux");
+        match backend {
+            BibBackend::Biber => self.biber(Some(&aux))?,
+            _ => self.bibtex(Some(&aux))?,
+        };
+
+        let bbl = self.output_file("bbl");
+        if !bbl.exists() {
+            let err = Error::new(ErrorKind::BblNotFound(bbl));
+            return match self.config.abort_if_error {
+                true => {
+                    error!("{}{}", self.tag(), err);
+                    Err(err)
+                }
+                false => {
+                    warn!("{}{}", self.tag(), err);
+                    Ok(self)
+                }
+            };
+        }
+
+        Ok(self)
+    }
+
+    /// Compile the main tex with the configured engine,
     /// pass in main tex as `file`
-    pub fn pdflatex(&self, file: Option<&PathBuf>) -> std::result::Result<&Self, Error> {
+    pub fn compile(&self, file: Option<&PathBuf>) -> std::result::Result<&Self, Error> {
         let main_tex = match file {
             Some(file) => file,
             None => &self.config.main_tex,
         };
+        let engine = self.config.engine;
 
-        info!("Running pdfLaTeX for {}", main_tex.display());
-        let mut command = Command::new("pdflatex"); // FIXME: specify pdflatex path
+        info!(
+            "{}Running {} for {}",
+            self.tag(),
+            engine,
+            main_tex.display()
+        );
+        let mut command = self.config.tools.command(engine.tool());
         command
-            .arg("-interaction")
-            .arg("nonstopmode")
-            .arg("-output-directory") // explicitly specify the output directory
-            .arg(&self.config.project_dir.as_os_str())
+            .args(engine.flags(&self.config.project_dir))
+            .args(&self.config.engine_args)
             .arg(main_tex) // main_tex comes the last, the position of args matters on some LaTeX distributions
-            .stdout(Stdio::null()) // TODO: Maybe pipe to log?
-            .stderr(Stdio::null()) // TODO: Maybe pipe to log?
             .current_dir(&self.config.project_dir); // Run pdflatex in project dir by default
 
         debug!("CommandLineArgs: {:?}", command);
         debug!("WorkDir: {}", self.config.project_dir.display());
 
-        let ecode = command.spawn().unwrap().wait().unwrap();
+        let ecode = self.execute(engine.binary(), &mut command)?;
+        let diagnostics = self.report(main_tex);
 
-        // TODO: Refactor this later
-        match (ecode.success(), self.config.abort_if_error) {
-            (true, _) => {
-                info!("{}", "Compilation SUCCESS".green().bold().underlined())
-            }
-            (false, false) => {
-                warn!("{}", "Compilation FAIL".yellow().bold().underlined())
-            }
-            (false, true) => {
-                error!("{}", "Compilation FAIL".red().bold().underlined());
-                return Err(Error::new(ErrorKind::CompileError(String::from(
-                    "pdflatex",
-                ))));
+        self.check(engine.binary(), ecode, diagnostics)?;
+
+        Ok(self)
+    }
+
+    /// Compile the main tex again and again until the log stops asking for
+    /// a rerun, or the configured maximum of passes is reached
+    pub fn compile_until_stable(&self) -> std::result::Result<&Self, Error> {
+        let max_passes = self.config.max_passes.max(1);
+        for pass in 1..=max_passes {
+            info!("{}Compilation pass {}/{}", self.tag(), pass, max_passes);
+            self.config.progress.emit(ProgressEvent::Pass {
+                version: self.config.version.clone(),
+                pass,
+                max_passes,
+            });
+            self.compile(None)?;
+            if !texlog::needs_rerun(self.output_file("log")) {
+                info!("{}Output is stable after {} pass(es)", self.tag(), pass);
+                return Ok(self);
             }
         }
+        warn!(
+            "{}Output is still not stable after {} passes",
+            self.tag(),
+            max_passes
+        );
+        Ok(self)
+    }
+
+    /// Hand the whole build to latexmk, so that `.latexmkrc`, custom dependencies,
+    /// makeindex, glossaries and rerun detection are taken care of by latexmk,
+    /// pass in main tex as `file`
+    pub fn latexmk(&self, file: Option<&PathBuf>) -> std::result::Result<&Self, Error> {
+        let main_tex = match file {
+            Some(file) => file,
+            None => &self.config.main_tex,
+        };
+        let engine = self.config.engine;
+
+        info!(
+            "{}Running latexmk ({}) for {}",
+            self.tag(),
+            engine,
+            main_tex.display()
+        );
+        let mut outdir = OsString::from("-outdir=");
+        outdir.push(&self.config.project_dir);
+
+        let mut command = self.config.tools.latexmk_command();
+        command
+            .arg(engine.latexmk_flag())
+            .arg("-interaction=nonstopmode")
+            .arg("-file-line-error")
+            .arg(outdir) // explicitly specify the output directory
+            .args(self.config.engine_args.iter().map(|arg| {
+                // latexmk hands them over to the engine
+                let mut option = OsString::from("-latexoption=");
+                option.push(arg);
+                option
+            }))
+            .arg(main_tex)
+            .current_dir(&self.config.project_dir); // latexmk picks up the `.latexmkrc` in project dir
+
+        debug!("CommandLineArgs: {:?}", command);
+        debug!("WorkDir: {}", self.config.project_dir.display());
+
+        let ecode = self.execute("latexmk", &mut command)?;
+        let diagnostics = self.report(main_tex);
+
+        self.check("latexmk", ecode, diagnostics)?;
 
         Ok(self)
     }
@@ -205,8 +866,8 @@ impl LaTeX {
                 let aux = self.ext_finder("aux").pop();
                 if aux.is_none() {
                     return match self.config.abort_if_error {
-                        true => Err(Error::new(ErrorKind::CompileError(String::from("bibtex")))), // TODO: Maybe add a new error kind
-                        false => Ok(&self),
+                        true => Err(self.compile_error("bibtex", Vec::new())), // TODO: Maybe add a new error kind
+                        false => Ok(self),
                     };
                 }
                 let mut aux = aux.unwrap();
@@ -220,36 +881,50 @@ impl LaTeX {
             }
         };
 
-        info!("Running bibtex for {}", aux.display());
+        info!("{}Running bibtex for {}", self.tag(), aux.display());
 
-        let mut command = Command::new("bibtex"); // FIXME: specify bibtex path
+        let mut command = self.config.tools.command(ToolName::Bibtex);
 
         command
+            .args(&self.config.bib_args)
             .arg(&aux)
-            .stdout(Stdio::null()) // TODO: Maybe pipe to log?
-            .stderr(Stdio::null()) // TODO: Maybe pipe to log?
             .current_dir(&self.config.project_dir);
 
         debug!("CommandLineArgs: {:?}", command);
         debug!("WorkDir: {}", self.config.project_dir.display());
 
-        let ecode = command.spawn().unwrap().wait().unwrap();
+        let ecode = self.execute("bibtex", &mut command)?;
 
-        // TODO: Refactor this later
-        match (ecode.success(), self.config.abort_if_error) {
-            (true, _) => {
-                info!("{}", "Compilation SUCCESS".green().bold().underlined())
-            }
-            (false, false) => {
-                warn!("{}", "Compilation FAIL".yellow().bold().underlined())
-            }
-            (false, true) => {
-                error!("{}", "Compilation FAIL".red().bold().underlined());
-                return Err(Error::new(ErrorKind::CompileError(String::from("bibtex"))));
-            }
-        }
+        self.check("bibtex", ecode, Vec::new())?;
 
-        Ok(&self)
+        Ok(self)
+    }
+
+    /// pass in the `.bcf` (or any file with the same stem) as `file`
+    pub fn biber(&self, file: Option<&PathBuf>) -> std::result::Result<&Self, Error> {
+        let mut bcf = match file {
+            Some(file) => file.to_owned(),
+            None => self.output_file("bcf"),
+        };
+        bcf.set_extension("");
+
+        info!("{}Running biber for {}", self.tag(), bcf.display());
+
+        let mut command = self.config.tools.command(ToolName::Biber);
+
+        command
+            .args(&self.config.bib_args)
+            .arg(&bcf)
+            .current_dir(&self.config.project_dir);
+
+        debug!("CommandLineArgs: {:?}", command);
+        debug!("WorkDir: {}", self.config.project_dir.display());
+
+        let ecode = self.execute("biber", &mut command)?;
+
+        self.check("biber", ecode, Vec::new())?;
+
+        Ok(self)
     }
 
     pub fn expand(
@@ -268,6 +943,65 @@ impl LaTeX {
             None => &self.config.main_tex,
         };
 
+        let bbl = match bbl {
+            // if bbl is not given, prefer the one of main tex, then find in the project dir
+            Some(bbl) => Some(bbl.to_owned()),
+            // biblatex reads the bbl by itself rather than in place of `\bibliography`,
+            // so biber runs again for the diff instead
+            None if self.bib_backend() == Some(BibBackend::Biber) => None,
+            None => match self.output_file("bbl") {
+                bbl if bbl.exists() => Some(bbl),
+                _ => self.ext_finder("bbl").pop(),
+            },
+        };
+
+        info!("{}Expanding Source: {}", self.tag(), &file.display());
+        info!("{}Expanding Target: {}", self.tag(), &out.display());
+
+        match self.config.expander {
+            Expander::Native => self.flatten(file, out, bbl)?,
+            Expander::Latexpand => self.latexpand(file, out, bbl)?,
+        };
+
+        Ok(self)
+    }
+
+    fn flatten(&self, file: &Path, out: &Path, bbl: Option<PathBuf>) -> Result<&Self, Error> {
+        let mut flattener = Flattener::new(file.parent().unwrap().to_path_buf()).bbl(bbl);
+        let content = match flattener.flatten(file) {
+            Ok(content) => content,
+            Err(e) => {
+                error!("{}Fail to read {}: {}", self.tag(), file.display(), e);
+                return Err(self.compile_error("flatten", Vec::new()));
+            }
+        };
+
+        let missing = flattener.missing();
+        for file in missing {
+            warn!(
+                "{}Can not find {}, leave it unexpanded",
+                self.tag(),
+                file.display()
+            );
+        }
+        if !missing.is_empty() && self.config.abort_if_error {
+            return Err(Error::new(ErrorKind::InputNotFound(missing.to_vec())));
+        }
+
+        fs::write(out, content).map_err(|e| {
+            error!("{}Fail to write {}: {}", self.tag(), out.display(), e);
+            Error::new(ErrorKind::IoError(out.to_path_buf(), e.to_string()))
+        })?;
+        info!(
+            "{}{}",
+            self.tag(),
+            "Expansion SUCCESS".green().bold().underlined()
+        );
+
+        Ok(self)
+    }
+
+    fn latexpand(&self, file: &Path, out: &Path, bbl: Option<PathBuf>) -> Result<&Self, Error> {
         let mut real_out = out.to_owned();
         if file == out {
             // if input is same as output name
@@ -279,56 +1013,26 @@ impl LaTeX {
             real_out.push(format!("_{}", tmp.to_str().unwrap()));
         }
 
-        let bbl = match bbl {
-            // if bbl is not given, find in the project dir
-            Some(bbl) => bbl.to_owned(),
-            None => {
-                let bbl = self.ext_finder("bbl").pop();
-                if bbl.is_none() {
-                    return match self.config.abort_if_error {
-                        true => Err(Error::new(ErrorKind::CompileError(String::from(
-                            "latexpand",
-                        )))), // TODO: Maybe add a new error kind
-                        false => Ok(&self),
-                    };
-                }
-                bbl.unwrap()
-            }
-        };
-
-        info!("Expanding Source: {}", &file.display());
-        info!("Expanding Target: {}", &out.display());
-
-        let mut command = Command::new("latexpand"); // FIXME: specify latexpand path
+        let mut command = self.config.tools.command(ToolName::Latexpand);
 
         command
-            .arg(&file)
+            .args(&self.config.expand_args)
+            .arg(file)
             .arg("--output")
             .arg(&real_out)
-            .arg("--expand-bbl")
-            .arg(&bbl)
-            .current_dir(&file.parent().unwrap()); // The working directory should be set
+            .current_dir(file.parent().unwrap()); // The working directory should be set
+
+        // documents without bibliography have nothing to expand
+        if let Some(bbl) = &bbl {
+            command.arg("--expand-bbl").arg(bbl);
+        }
 
         debug!("CommandLineArgs: {:?}", command);
         debug!("WorkDir: {}", self.config.project_dir.display());
 
-        let ecode = command.spawn().unwrap().wait().unwrap();
+        let ecode = self.execute("latexpand", &mut command)?;
 
-        // TODO: Refactor this later
-        match (ecode.success(), self.config.abort_if_error) {
-            (true, _) => {
-                info!("{}", "Compilation SUCCESS".green().bold().underlined())
-            }
-            (false, false) => {
-                warn!("{}", "Compilation FAIL".yellow().bold().underlined())
-            }
-            (false, true) => {
-                error!("{}", "Compilation FAIL".red().bold().underlined());
-                return Err(Error::new(ErrorKind::CompileError(String::from(
-                    "latexpand",
-                ))));
-            }
-        }
+        self.check("latexpand", ecode, Vec::new())?;
 
         if file == out {
             fs::rename(real_out, out).unwrap();
@@ -337,44 +1041,433 @@ impl LaTeX {
         Ok(self)
     }
 
-    pub fn diff(config: &config::Config, old: &PathBuf, new: &PathBuf, out: &PathBuf) {
-        // FIXME: this function need to be refactored
+    /// Run latexdiff on the flattened `old` and `new`, and write the diff result to `out`
+    pub fn diff(&self, old: &Path, new: &Path, out: &Path) -> Result<&Self, Error> {
         info!("Diff Source {}", old.display());
         info!("Diff Source {}", new.display());
         info!("Diff Output {}", out.display());
         // pipe to a standalone file
-        let diff_result = File::create(out).unwrap();
-        let stdio = Stdio::from(diff_result);
-
-        let mut command = Command::new(&config.latexdiff_path);
+        let diff_result = File::create(out)
+            .map_err(|e| Error::new(ErrorKind::IoError(out.to_path_buf(), e.to_string())))?;
 
+        let mut command = self.config.tools.command(ToolName::Latexdiff);
         command
-            .arg(&old)
-            .arg(&new)
-            .args(&config.latexdiff_args)
+            .arg(old)
+            .arg(new)
+            .args(&self.config.latexdiff_args)
             // .arg("--flatten") // FIXME: Sometimes Strange, So remove this args
-            .stderr(Stdio::null()) // TODO: Maybe pipe to log?
-            .stdout(stdio);
+            .stdout(Stdio::from(diff_result));
 
         debug!("CommandLineArgs: {:?}", command);
 
-        let ecode = command.spawn().unwrap().wait().unwrap();
+        // stdout is the diff result, only stderr is captured
+        let ecode = self.execute_capturing("latexdiff", &mut command, false)?;
+        self.check("latexdiff", ecode, Vec::new())?;
 
-        // TODO: Refactor this later
-        match ecode.success() {
-            true => {
-                info!("{}", "Diff SUCCESS".green().bold().underlined())
-            }
-            false => {
-                error!("{}", "Diff FAIL".red().bold().underlined())
-            }
+        Ok(self)
+    }
+}
+
+/// Infer the bibliography backend from the source of the main TeX
+fn bib_backend_of(tex: &str) -> Option<BibBackend> {
+    let code = tex
+        .lines()
+        .map(|line| split_comment(line).0)
+        .collect::<Vec<_>>()
+        .join("\n");
+    let preamble = code.split("\\begin{document}").next().unwrap_or_default();
+
+    let biblatex = Regex::new(r"\\usepackage\s*(?:\[([^\]]*)\])?\s*\{biblatex\}").unwrap();
+    if let Some(caps) = biblatex.captures(preamble) {
+        let backend = Regex::new(r"backend\s*=\s*(\w+)").unwrap();
+        let options = caps.get(1).map_or("", |options| options.as_str());
+        // biber is the default backend of biblatex, bibtex8 and bibtexu are variants of bibtex
+        return match backend.captures(options) {
+            Some(caps) if caps[1].starts_with("bibtex") => Some(BibBackend::Bibtex),
+            _ => Some(BibBackend::Biber),
+        };
+    }
+    let bibliography = Regex::new(r"\\bibliography\s*\{").unwrap();
+    match bibliography.is_match(&code) {
+        true => Some(BibBackend::Bibtex),
+        false => None,
+    }
+}
+
+/// Error of a tool that can not be run, either it is missing or it can not be executed
+fn io_error(command: &Command, e: io::Error) -> Error {
+    let program = PathBuf::from(command.get_program());
+    match e.kind() {
+        io::ErrorKind::NotFound => {
+            Error::new(ErrorKind::BinaryNotFound(program.display().to_string()))
+        }
+        _ => Error::new(ErrorKind::IoError(program, e.to_string())),
+    }
+}
+
+/// Spawn the child in a process group of its own, so that the processes
+/// it spawns, e.g. the engines run by latexmk, can be killed along with it
+fn spawn(command: &mut Command) -> io::Result<Child> {
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command, 0);
+    command.spawn()
+}
+
+/// Kill the child and every process in its group
+fn kill(child: &mut Child) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        // the id of the group is the one of the child, see `spawn`
+        let pgid = child.id() as libc::pid_t;
+        if unsafe { libc::kill(-pgid, libc::SIGKILL) } == 0 {
+            return Ok(());
+        }
+    }
+    child.kill()
+}
+
+/// Wait for the child to exit, kill it once `cancel` is set
+fn wait(child: &mut Child, cancel: &AtomicBool) -> io::Result<ExitStatus> {
+    loop {
+        if cancel.load(Ordering::SeqCst) {
+            // the child may have exited in the meantime, but not its children
+            let _ = kill(child);
+            return child.wait();
         }
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        thread::sleep(Duration::from_millis(50));
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
+/// Print the output of a child line by line with `prefix`
+fn forward<R: Read>(reader: R, prefix: &str, stderr: bool) {
+    let mut reader = BufReader::new(reader);
+    let mut buf = Vec::new();
+    // output of TeX is not always valid UTF-8, so don't use `lines()`
+    while let Ok(n) = reader.read_until(b'\n', &mut buf) {
+        if n == 0 {
+            break;
+        }
+        let line = String::from_utf8_lossy(&buf);
+        match stderr {
+            true => eprintln!("{} {}", prefix.dark_grey(), line.trim_end()),
+            false => println!("{} {}", prefix.dark_grey(), line.trim_end()),
+        }
+        buf.clear();
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capture {
+    /// Discard the output of the tools
+    #[default]
+    Quiet,
+    /// Stream the output of the tools to the terminal
+    Stream,
+    /// Write the output of the tools into `<tmp-dir>/logs`
+    File,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum Engine {
+    #[default]
     Pdflatex,
     Xelatex,
     Lualatex,
 }
+
+impl Engine {
+    /// Name of the executable of the engine
+    pub fn binary(&self) -> &'static str {
+        self.tool().binary()
+    }
+
+    pub fn tool(&self) -> ToolName {
+        match self {
+            Engine::Pdflatex => ToolName::Pdflatex,
+            Engine::Xelatex => ToolName::Xelatex,
+            Engine::Lualatex => ToolName::Lualatex,
+        }
+    }
+
+    /// Flag that tells latexmk which engine to use
+    pub fn latexmk_flag(&self) -> &'static str {
+        match self {
+            Engine::Pdflatex => "-pdf",
+            Engine::Xelatex => "-pdfxe",
+            Engine::Lualatex => "-pdflua",
+        }
+    }
+
+    /// Engine specific flags, which should be placed before the main tex
+    pub fn flags(&self, output_dir: &Path) -> Vec<OsString> {
+        match self {
+            Engine::Pdflatex | Engine::Xelatex => vec![
+                OsString::from("-interaction"),
+                OsString::from("nonstopmode"),
+                OsString::from("-file-line-error"), // errors in `file:line: message` form
+                OsString::from("-output-directory"), // explicitly specify the output directory
+                output_dir.as_os_str().to_owned(),
+            ],
+            // LuaTeX only accepts the `--key=value` form of options
+            Engine::Lualatex => {
+                let mut output_directory = OsString::from("--output-directory=");
+                output_directory.push(output_dir);
+                vec![
+                    OsString::from("--interaction=nonstopmode"),
+                    OsString::from("--file-line-error"),
+                    output_directory,
+                ]
+            }
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Engine::Pdflatex => write!(f, "pdfLaTeX"),
+            Engine::Xelatex => write!(f, "XeLaTeX"),
+            Engine::Lualatex => write!(f, "LuaLaTeX"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Builder {
+    /// Run the engine and bibtex step by step
+    #[default]
+    Native,
+    /// Hand the compilation to latexmk, which honors `.latexmkrc`
+    Latexmk,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Expander {
+    /// Flatten the sources in process
+    #[default]
+    Native,
+    /// Run the external latexpand
+    Latexpand,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BibBackend {
+    /// Infer from the `.bcf`, `.aux` and the preamble
+    #[default]
+    Auto,
+    Bibtex,
+    Biber,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn biblatex_defaults_to_biber() {
+        let tex = "\\usepackage{biblatex}\n\\begin{document}\n\\printbibliography\n";
+        assert_eq!(bib_backend_of(tex), Some(BibBackend::Biber));
+    }
+
+    #[test]
+    fn biblatex_backend_with_spaces() {
+        let tex = "\\usepackage[\n  style=alphabetic,\n  backend = bibtex8,\n]{biblatex}\n";
+        assert_eq!(bib_backend_of(tex), Some(BibBackend::Bibtex));
+        let tex = "\\usepackage [backend= biber] {biblatex}\n";
+        assert_eq!(bib_backend_of(tex), Some(BibBackend::Biber));
+    }
+
+    #[test]
+    fn commented_out_lines_are_ignored() {
+        let tex = "% \\usepackage[backend=biber]{biblatex}\n\\bibliography{refs}\n";
+        assert_eq!(bib_backend_of(tex), Some(BibBackend::Bibtex));
+        let tex = "\\usepackage[backend=bibtex]{biblatex} % backend=biber\n";
+        assert_eq!(bib_backend_of(tex), Some(BibBackend::Bibtex));
+        let tex = "%\\bibliography{refs}\n50\\% done\n";
+        assert_eq!(bib_backend_of(tex), None);
+    }
+
+    #[test]
+    fn biblatex_in_body_is_not_a_package() {
+        let tex = "\\begin{document}\n\\usepackage{biblatex}\n\\end{document}\n";
+        assert_eq!(bib_backend_of(tex), None);
+    }
+
+    #[test]
+    fn bibliography_with_spaces() {
+        let tex = "\\begin{document}\n\\bibliography {refs}\n\\end{document}\n";
+        assert_eq!(bib_backend_of(tex), Some(BibBackend::Bibtex));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cancel_kills_the_children_of_the_tool() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let main_tex = dir.path().join("main.tex");
+        fs::write(&main_tex, "").unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let tex = LaTeX::new(
+            ConfigBuilder::new()
+                .project_dir(dir.path().to_path_buf())
+                .main_tex(main_tex)
+                .capture(Capture::Stream)
+                .cancel(cancel.clone())
+                .build()
+                .unwrap(),
+        );
+        let setter = {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                cancel.store(true, Ordering::SeqCst);
+            })
+        };
+        let start = std::time::Instant::now();
+        // the streamed output is only closed once the grandchild is gone
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("sleep 30 & sleep 30");
+        let ecode = tex.spawn_and_wait("sh", &mut command, true).unwrap();
+        setter.join().unwrap();
+        assert!(!ecode.success());
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    /// A project with `files` given as `(path, content)`
+    fn project(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::TempDir::new().unwrap();
+        for (path, content) in files {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    /// Guess the main TeX of a project, relative to the root of the project
+    fn guess(files: &[(&str, &str)]) -> Result<PathBuf, Error> {
+        let dir = project(files);
+        // the magic comments are resolved into canonical paths
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let config = ConfigBuilder::new().project_dir(root.clone()).build()?;
+        Ok(config.main_tex.strip_prefix(&root).unwrap().to_path_buf())
+    }
+
+    const DOCUMENT: &str = "\\documentclass{article}\n\\begin{document}\n\\end{document}\n";
+
+    #[test]
+    fn equally_likely_candidates_are_ambiguous() {
+        let err = guess(&[("one.tex", DOCUMENT), ("two.tex", DOCUMENT)]).unwrap_err();
+        match err.kind() {
+            ErrorKind::AmbiguousMainTeX(candidates) => {
+                let mut candidates = candidates.clone();
+                candidates.sort();
+                assert_eq!(
+                    candidates,
+                    vec![PathBuf::from("one.tex"), PathBuf::from("two.tex")]
+                );
+            }
+            kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn candidates_in_asset_dirs_rank_lower() {
+        let dir = project(&[
+            ("figures/plot.tex", DOCUMENT),
+            ("src/report.tex", DOCUMENT),
+            (
+                "src/table.tex",
+                "\\documentclass{standalone}\n\\begin{document}\n",
+            ),
+        ]);
+        let candidates = ["figures/plot.tex", "src/report.tex", "src/table.tex"]
+            .iter()
+            .map(|path| dir.path().join(path))
+            .collect();
+        let ranked: Vec<_> = ConfigBuilder::rank(dir.path(), candidates)
+            .into_iter()
+            .map(|(path, _)| path.strip_prefix(dir.path()).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(
+            ranked,
+            vec![
+                PathBuf::from("src/report.tex"),
+                PathBuf::from("src/table.tex"),
+                PathBuf::from("figures/plot.tex"),
+            ]
+        );
+    }
+
+    #[test]
+    fn main_names_win_the_tie() {
+        let guessed = guess(&[("notes.tex", DOCUMENT), ("thesis.tex", DOCUMENT)]);
+        assert_eq!(guessed.unwrap(), PathBuf::from("thesis.tex"));
+    }
+
+    #[test]
+    fn magic_comment_names_the_root() {
+        let guessed = guess(&[
+            ("one.tex", DOCUMENT),
+            ("two.tex", DOCUMENT),
+            ("chapters/intro.tex", "% !TEX root = ../two.tex\nIntro\n"),
+        ]);
+        assert_eq!(guessed.unwrap(), PathBuf::from("two.tex"));
+    }
+
+    #[test]
+    fn latexmkrc_names_the_default_file() {
+        let guessed = guess(&[
+            ("one.tex", DOCUMENT),
+            ("two.tex", DOCUMENT),
+            (".latexmkrc", "@default_files = ('two');\n"),
+        ]);
+        assert_eq!(guessed.unwrap(), PathBuf::from("two.tex"));
+    }
+
+    #[test]
+    fn latexmain_marks_the_main() {
+        let guessed = guess(&[
+            ("one.tex", DOCUMENT),
+            ("two.tex", DOCUMENT),
+            ("two.tex.latexmain", ""),
+        ]);
+        assert_eq!(guessed.unwrap(), PathBuf::from("two.tex"));
+    }
+
+    /// Flatten `main.tex` of a project with `files` given as `(path, content)`
+    fn expand(files: &[(&str, &str)]) -> String {
+        let dir = project(files);
+        let main_tex = dir.path().join("main.tex");
+        let tex = LaTeX::new(
+            ConfigBuilder::new()
+                .project_dir(dir.path().to_path_buf())
+                .main_tex(main_tex.clone())
+                .build()
+                .unwrap(),
+        );
+        tex.expand(None, None, None).unwrap();
+        fs::read_to_string(main_tex).unwrap()
+    }
+
+    #[test]
+    fn bibtex_bbl_is_inlined() {
+        let tex = "\\bibliographystyle{plain}\n\\bibliography{refs}\n";
+        let content = expand(&[("main.tex", tex), ("main.bbl", "BBL")]);
+        assert_eq!(content, "\nBBL\n");
+    }
+
+    #[test]
+    fn biblatex_bbl_is_left_to_biber() {
+        let tex = "\\usepackage{biblatex}\n\\addbibresource{refs.bib}\n\\printbibliography\n";
+        let content = expand(&[("main.tex", tex), ("main.bcf", "BCF"), ("main.bbl", "BBL")]);
+        assert_eq!(content, tex);
+    }
+}
@@ -0,0 +1,90 @@
+//! Diff two versions of a LaTeX project in a git repo with latexdiff.
+//!
+//! ```no_run
+//! use git_latexdiff::ConfigBuilder;
+//!
+//! let outcome = git_latexdiff::diff(".", "HEAD~1", "HEAD", ConfigBuilder::new()).unwrap();
+//! println!("Diff result placed in {}", outcome.artifacts.pdf.display());
+//! if !outcome.is_clean() {
+//!     println!("The diff may be broken, failed: {}", outcome.failures.join(", "));
+//! }
+//! ```
+//!
+//! Settings of the config files, e.g. `.git-latexdiff.toml`, are taken as the base of the options:
+//!
+//! ```no_run
+//! use git_latexdiff::settings::Settings;
+//! use git_latexdiff::ConfigBuilder;
+//! use std::path::Path;
+//!
+//! let settings = Settings::load(Path::new("."), None).unwrap();
+//! let options = ConfigBuilder::from_settings(settings).max_passes(3);
+//! git_latexdiff::diff(".", "HEAD~1", "HEAD", options).unwrap();
+//! ```
+//!
+//! Drive a [`Runner`] to follow the progress of the run:
+//!
+//! ```no_run
+//! use git_latexdiff::{ConfigBuilder, ProgressEvent, Runner};
+//! use std::sync::Arc;
+//!
+//! let config = ConfigBuilder::new()
+//!     .old_hash(Some(String::from("HEAD~1")))
+//!     .new_hash(Some(String::from("HEAD")))
+//!     .build()
+//!     .unwrap();
+//! let mut runner = Runner::new(config)
+//!     .unwrap()
+//!     .progress(Arc::new(|event: &ProgressEvent| println!("{:?}", event)));
+//! runner.run().unwrap();
+//! ```
+
+pub mod args;
+mod cache;
+pub mod config;
+pub mod error;
+mod flatten;
+mod git;
+pub mod latex;
+pub mod logger;
+pub mod progress;
+pub mod runner;
+pub mod selector;
+pub mod settings;
+pub mod texlog;
+mod tmp_dir;
+pub mod tools;
+pub mod util;
+pub mod wrapper;
+
+mod item;
+
+pub use crate::config::{Config, ConfigBuilder};
+pub use crate::error::{Error, ErrorKind};
+pub use crate::latex::LaTeX;
+pub use crate::progress::{ProgressEvent, ProgressSink};
+pub use crate::runner::{DiffArtifacts, DiffOutcome, Runner};
+pub use crate::wrapper::CommitWrapper;
+
+use std::path::Path;
+
+#[macro_use]
+extern crate log;
+
+/// Diff `old` against `new` of the repo containing `repo`, both versions take
+/// anything accepted by `--old` and `--new`, e.g. a commit, `index` or `worktree`.
+/// The repo and the versions given in `options` are overridden.
+/// Tools that have failed without aborting the run are listed in the outcome.
+pub fn diff<P: AsRef<Path>>(
+    repo: P,
+    old: &str,
+    new: &str,
+    options: ConfigBuilder,
+) -> Result<DiffOutcome, Error> {
+    let config = options
+        .repo_dir(Some(repo.as_ref().to_path_buf()))
+        .old_hash(Some(String::from(old)))
+        .new_hash(Some(String::from(new)))
+        .build()?;
+    Runner::new(config)?.run()
+}
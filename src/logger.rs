@@ -1,7 +1,7 @@
 use clap::ValueEnum;
 use log::LevelFilter;
 
-#[derive(Ord, PartialOrd, Eq, PartialEq, Debug, Clone, ValueEnum)]
+#[derive(Ord, PartialOrd, Eq, PartialEq, Debug, Clone, Copy, ValueEnum)]
 pub enum LogLevel {
     /// A level lower than all log levels.
     Off,
@@ -19,13 +19,13 @@ pub enum LogLevel {
 
 impl LogLevel {
     pub fn to_level_filter(self) -> LevelFilter {
-        return match self {
+        match self {
             LogLevel::Off => LevelFilter::Off,
             LogLevel::Error => LevelFilter::Error,
             LogLevel::Warn => LevelFilter::Warn,
             LogLevel::Info => LevelFilter::Info,
             LogLevel::Debug => LevelFilter::Debug,
             LogLevel::Trace => LevelFilter::Trace,
-        };
+        }
     }
 }
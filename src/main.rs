@@ -1,21 +1,13 @@
-mod args;
-mod config;
-mod error;
-mod git;
-mod latex;
-mod logger;
-mod runner;
-mod selector;
-mod util;
-mod wrapper;
-
 use clap::Parser;
+use crossterm::style::Stylize;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
 
-#[cfg(not(windows))]
-mod item;
-
-use crate::config::Config;
-use crate::runner::Runner;
+use git_latexdiff::progress::LogSink;
+use git_latexdiff::settings::Settings;
+use git_latexdiff::tools::Tools;
+use git_latexdiff::{args, logger, selector, util, Config, Error, ErrorKind, Runner};
 
 #[macro_use]
 extern crate log;
@@ -23,17 +15,46 @@ extern crate simplelog;
 
 use simplelog::*;
 
-fn main() {
-    let args: args::Args = args::Args::parse();
+/// Log the error and map it to the exit code, see the `after_help` of [`args::Args`]
+fn fail(err: &Error) -> ExitCode {
+    error!("{}", err);
+    let code = match err.kind() {
+        ErrorKind::CompileError { stage, version, .. } => {
+            error!(
+                "{}",
+                format!(
+                    "Aborted: {} failed when building the {} version",
+                    stage, version
+                )
+                .red()
+                .bold()
+            );
+            2
+        }
+        ErrorKind::InputNotFound(_) | ErrorKind::BblNotFound(_) => 2,
+        // interrupted by SIGINT or SIGTERM, or no commit is selected
+        ErrorKind::Cancelled | ErrorKind::SkimAbort => 130,
+        _ => 1,
+    };
+    ExitCode::from(code)
+}
 
-    if args.health_check {
-        util::health_check();
-        return;
-    }
+fn main() -> ExitCode {
+    let mut args = match args::Args::try_parse() {
+        Ok(args) => args,
+        Err(err) => {
+            let _ = err.print();
+            // clap exits with 2 on invalid args, which means a failed tool here
+            return match err.use_stderr() {
+                true => ExitCode::from(1),
+                false => ExitCode::SUCCESS, // --help or --version
+            };
+        }
+    };
 
     // Init the global logger
     CombinedLogger::init(vec![TermLogger::new(
-        args.log_level.clone().to_level_filter(),
+        args.log_level.to_level_filter(),
         simplelog::ConfigBuilder::default()
             .add_filter_allow_str("git_latexdiff")
             .set_target_level(LevelFilter::Off)
@@ -46,27 +67,75 @@ fn main() {
     )])
     .unwrap();
 
-    let log_level = args.log_level.clone();
+    let log_level = args.log_level;
+
+    // fzf runs us for the preview of an item
+    if let Some(version) = &args.preview_item {
+        let repo_dir = args.repo_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+        return match selector::preview(&repo_dir, version) {
+            Ok(preview) => {
+                println!("{}", preview);
+                ExitCode::SUCCESS
+            }
+            Err(err) => fail(&err),
+        };
+    }
+
+    // the settings in config files fill the args that are not given
+    let repo_dir = match &args.repo_dir {
+        Some(dir) => dir.clone(),
+        None => match std::env::current_dir() {
+            Ok(dir) => dir,
+            Err(e) => {
+                return fail(&Error::new(ErrorKind::IoError(
+                    PathBuf::from("."),
+                    e.to_string(),
+                )))
+            }
+        },
+    };
+    match Settings::load(&repo_dir, args.profile.as_deref()) {
+        Ok(settings) => settings.apply(&mut args),
+        Err(err) => return fail(&err),
+    }
+
+    if args.health_check {
+        util::health_check(&Tools::from(&args));
+        return ExitCode::SUCCESS;
+    }
 
     if log_level >= logger::LogLevel::Debug {
         println!("{:#?}", args);
     }
 
-    let config = Config::from(args);
+    let config = match Config::try_from(args) {
+        Ok(config) => config,
+        Err(err) => return fail(&err),
+    };
 
     if log_level >= logger::LogLevel::Debug {
         println!("{:#?}", config);
     }
 
-    let mut runner = Runner::new(config).unwrap_or_else(|err| {
-        // tmp dir is not created yet
-        println!("{}", err);
-        std::process::exit(1);
-    });
+    let mut runner = match Runner::new(config) {
+        Ok(runner) => runner.progress(Arc::new(LogSink::default())),
+        Err(err) => return fail(&err),
+    };
 
-    runner.run().unwrap_or_else(|err| {
-        runner.abort(Err(err));
-    });
+    // stop the running tools and clean up on Ctrl-C or `kill`
+    let cancel = runner.cancel.clone();
+    ctrlc::set_handler(move || {
+        if cancel.swap(true, std::sync::atomic::Ordering::SeqCst) {
+            // interrupted again while cleaning up, give up
+            std::process::exit(130);
+        }
+        warn!("Interrupted, stopping the running tools");
+    })
+    .unwrap_or_else(|err| warn!("Fail to handle the signals: {}", err));
 
-    runner.abort(Ok(()));
+    // the tmp dir is cleaned up once the runner is dropped
+    match runner.run() {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => fail(&err),
+    }
 }
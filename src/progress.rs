@@ -0,0 +1,132 @@
+use crate::texlog::Diagnostic;
+use crossterm::style::Stylize;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Stages of a run, in the order they are taken
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Stage {
+    Checkout,
+    Expand,
+    Diff,
+    Compile,
+}
+
+impl Stage {
+    pub const COUNT: usize = 4;
+
+    /// Starts from 1
+    pub fn index(&self) -> usize {
+        match self {
+            Stage::Checkout => 1,
+            Stage::Expand => 2,
+            Stage::Diff => 3,
+            Stage::Compile => 4,
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Checkout => write!(f, "Checking Out From Git Repo"),
+            Stage::Expand => write!(f, "Expanding The TeX File"),
+            Stage::Diff => write!(f, "Differing Two Flattened TeX file"),
+            Stage::Compile => write!(f, "Compiling Diff Result TeX file"),
+        }
+    }
+}
+
+/// What happens during a run, `version` is one of old, new or diff
+#[derive(Clone, Debug)]
+pub enum ProgressEvent {
+    StageStarted(Stage),
+    StageFinished(Stage),
+    /// An external tool is spawned, e.g. pdflatex
+    ToolInvoked {
+        version: String,
+        tool: String,
+    },
+    /// The engine is run for the `pass`-th time, out of at most `max_passes`
+    Pass {
+        version: String,
+        pass: usize,
+        max_passes: usize,
+    },
+    /// Problems found in the log of the engine
+    Diagnostics {
+        version: String,
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// The diff result is written to the output
+    ArtifactWritten(PathBuf),
+}
+
+/// Receiver of the progress events, may be called from several threads at once
+pub trait ProgressSink: Send + Sync {
+    fn event(&self, event: &ProgressEvent);
+}
+
+impl<F> ProgressSink for F
+where
+    F: Fn(&ProgressEvent) + Send + Sync,
+{
+    fn event(&self, event: &ProgressEvent) {
+        self(event)
+    }
+}
+
+/// Handle to the registered sink, events are dropped if there is none
+#[derive(Clone, Default)]
+pub struct Progress {
+    sink: Option<Arc<dyn ProgressSink>>,
+}
+
+impl Progress {
+    pub fn new(sink: Arc<dyn ProgressSink>) -> Self {
+        Progress { sink: Some(sink) }
+    }
+
+    pub fn emit(&self, event: ProgressEvent) {
+        if let Some(sink) = &self.sink {
+            sink.event(&event);
+        }
+    }
+}
+
+/// Render the stages in the log, which is what the CLI shows
+#[derive(Default)]
+pub struct LogSink {
+    started: Mutex<Option<Instant>>,
+}
+
+impl ProgressSink for LogSink {
+    fn event(&self, event: &ProgressEvent) {
+        match event {
+            ProgressEvent::StageStarted(stage) => {
+                *self.started.lock().unwrap() = Some(Instant::now());
+                info!(
+                    "{}",
+                    format!("Stage[{}/{}] {}", stage.index(), Stage::COUNT, stage)
+                        .yellow()
+                        .bold()
+                        .underlined()
+                );
+            }
+            ProgressEvent::StageFinished(stage) => {
+                if let Some(started) = self.started.lock().unwrap().take() {
+                    debug!(
+                        "Stage[{}/{}] finished in {:.1}s",
+                        stage.index(),
+                        Stage::COUNT,
+                        started.elapsed().as_secs_f32()
+                    );
+                }
+            }
+            // the runner and the builds log these themselves
+            _ => {}
+        }
+    }
+}
@@ -1,172 +1,484 @@
+use crate::cache::Cache;
+use crate::config::AbortPolicy;
 use crate::git::Git;
-use crate::latex::{ConfigBuilder, LaTeX};
+use crate::latex::{BibBackend, Builder, Capture, ConfigBuilder, Engine, LaTeX};
 use crate::Config;
+use chrono::Local;
 use crossterm::style::Stylize;
-use git2::Repository;
+use git2::{Oid, Repository};
 use std::fs;
 
 use crate::error::{Error, ErrorKind};
+use crate::progress::{Progress, ProgressEvent, ProgressSink, Stage};
 use crate::selector::SelectorBuilder;
+use crate::tmp_dir::TmpDir;
+use crate::tools::ToolName;
 use crate::wrapper::CommitWrapper;
-use std::path::PathBuf;
-use std::process::exit;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// What a successful run leaves behind
+#[derive(Debug, Clone)]
+pub struct DiffArtifacts {
+    /// The versions that are compared
+    pub old: CommitWrapper,
+    pub new: CommitWrapper,
+    /// The compiled diff, placed at the configured output
+    pub pdf: PathBuf,
+    /// Source of the diff generated by latexdiff, which is kept after the tmp dir is cleaned
+    pub tex: String,
+}
+
+/// What a successful run ends up with
+#[derive(Debug, Clone)]
+pub struct DiffOutcome {
+    pub artifacts: DiffArtifacts,
+    /// Tools that have failed without aborting the run, e.g. `old/bibtex`,
+    /// only possible with [`AbortPolicy::Continue`]
+    pub failures: Vec<String>,
+}
+
+impl DiffOutcome {
+    /// Whether every tool has succeeded, otherwise the diff may be broken
+    pub fn is_clean(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
 
 pub struct Runner {
     pub config: Config,
     pub repo: Arc<Repository>,
+    /// Set to stop the run, the running tool is killed
+    pub cancel: Arc<AtomicBool>,
+    /// `None` until the run is prepared
+    tmp_dir: Option<TmpDir>,
+    progress: Progress,
 }
 
 impl Runner {
     pub fn new(config: Config) -> std::result::Result<Self, Error> {
         // Repo checker
         let repo = match Repository::discover(&config.repo_dir) {
+            // See: https://github.com/rust-lang/git2-rs/issues/194
+            #[allow(clippy::arc_with_non_send_sync)]
             Ok(repo) => Arc::new(repo),
             Err(_) => {
                 return Err(Error::new(ErrorKind::RepoNotFound(config.repo_dir)));
             }
         };
 
-        Ok(Runner { config, repo })
+        Ok(Runner {
+            config,
+            repo,
+            cancel: Arc::new(AtomicBool::new(false)),
+            tmp_dir: None,
+            progress: Progress::default(),
+        })
+    }
+
+    /// Receive the progress of the run in `sink`
+    pub fn progress(mut self, sink: Arc<dyn ProgressSink>) -> Self {
+        self.progress = Progress::new(sink);
+        self
     }
 
     fn select(&self) -> std::result::Result<(CommitWrapper, CommitWrapper), Error> {
-        let selector = {
-            #[cfg(not(windows))]
-            {
-                SelectorBuilder::default().repo(self.repo.clone()).build()
-            }
-            #[cfg(windows)]
-            {
-                SelectorBuilder::default().build()
-            }
+        let selector = || {
+            SelectorBuilder::default()
+                .repo(self.repo.clone())
+                .kind(self.config.selector)
+                .fzf(self.config.tools.path(ToolName::Fzf))
+                .build()
         };
 
+        // pick both in one session if neither is given
+        if self.config.old.is_none() && self.config.new.is_none() {
+            return selector()?.select_pair();
+        }
+
         let old_ver = match &self.config.old {
-            None => CommitWrapper::Commit(selector.select()?),
+            None => selector()?.select("select OLD")?,
             Some(x) => CommitWrapper::parse(&self.repo, x)?,
         };
 
         let new_ver = match &self.config.new {
-            None => CommitWrapper::Commit(selector.select()?),
+            None => selector()?.select("select NEW")?,
             Some(x) => CommitWrapper::parse(&self.repo, x)?,
         };
 
         Ok((old_ver, new_ver))
     }
 
-    pub fn run(&mut self) -> std::result::Result<(), Error> {
-        self.prepare();
+    /// Build both versions and the diff of them, the tmp dir is cleaned once the runner is dropped
+    pub fn run(&mut self) -> std::result::Result<DiffOutcome, Error> {
+        let outcome = self.try_run();
+        // a cancellation only stops the run it happens in or before,
+        // so it is reset once the run is over rather than when it starts
+        self.cancel.store(false, Ordering::SeqCst);
+        outcome
+    }
+
+    fn try_run(&mut self) -> std::result::Result<DiffOutcome, Error> {
+        self.check_cancel()?;
+        self.prepare()?;
         // Select
         let (old_ver, new_ver) = self.select()?;
+        self.check_cancel()?;
         // Checking out
-        info!(
-            "{}",
-            "Stage[1/4] Checking Out From Git Repo"
-                .yellow()
-                .bold()
-                .underlined()
-        );
+        self.progress
+            .emit(ProgressEvent::StageStarted(Stage::Checkout));
         let git = Git::new(&self.config, self.repo.as_ref());
         let mut old_dir = self.config.tmp_dir.clone();
         let mut new_dir = self.config.tmp_dir.clone();
         old_dir.push("old");
         new_dir.push("new");
 
-        git.checkout_to(old_ver, old_dir.as_path());
-        git.checkout_to(new_ver, new_dir.as_path());
+        git.checkout_to(old_ver, old_dir.as_path())?;
+        git.checkout_to(new_ver, new_dir.as_path())?;
+        let (old_main_tex, new_main_tex) = self.locate_main_tex(&git, old_ver, new_ver);
+        let old_key = self.cache_key(&git, old_ver, &old_main_tex, self.config.old_engine);
+        let new_key = self.cache_key(&git, new_ver, &new_main_tex, self.config.new_engine);
+        self.progress
+            .emit(ProgressEvent::StageFinished(Stage::Checkout));
+        self.check_cancel()?;
 
-        info!(
-            "{}",
-            "Stage[2/4] Expanding The TeX File"
-                .yellow()
-                .bold()
-                .underlined()
-        );
-        let tex = LaTeX::new(ConfigBuilder::new().project_dir(old_dir.clone()).build()?);
-
-        tex.pdflatex(None)? // Run pdflatex to generate aux file
-            .bibtex(None)?
-            .expand(None, None, None)?;
-        let old_main_tex = tex.config.main_tex;
+        self.progress
+            .emit(ProgressEvent::StageStarted(Stage::Expand));
+        // either version fails, the other one is cancelled
+        let cancel = self.cancel.clone();
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let old_tex = self
+            .latex_config(
+                "old",
+                old_dir.clone(),
+                old_main_tex.map(|path| old_dir.join(path)),
+                self.config.old_engine,
+            )
+            .cancel(cancel.clone())
+            .failures(failures.clone());
+        let new_tex = self
+            .latex_config(
+                "new",
+                new_dir.clone(),
+                new_main_tex.map(|path| new_dir.join(path)),
+                self.config.new_engine,
+            )
+            .cancel(cancel.clone())
+            .failures(failures.clone());
 
-        let tex = LaTeX::new(ConfigBuilder::new().project_dir(new_dir.clone()).build()?);
-
-        tex.pdflatex(None)? // Run pdflatex to generate aux file
-            .bibtex(None)?
-            .expand(None, None, None)?;
-        let new_main_tex = tex.config.main_tex;
+        let builder = self.config.builder;
+        let cache = self.config.cache_dir.clone().map(Cache::new);
+        let expand = |tex: ConfigBuilder, version: &str, key: Option<Oid>, dir: &Path| {
+            if let (Some(cache), Some(key)) = (&cache, key) {
+                if let Some(main_tex) = cache.restore(key, dir) {
+                    info!("Reuse the cached build of {}", dir.display());
+                    return Ok(main_tex);
+                }
+            }
+            let result = Runner::expand(tex, builder);
+            // a tolerated failure leaves a broken build, which is not worth caching
+            let failed = || {
+                let prefix = format!("{}/", version);
+                let failures = failures.lock().unwrap();
+                failures.iter().any(|failure| failure.starts_with(&prefix))
+            };
+            match (&result, &cache, key) {
+                (Ok(main_tex), Some(cache), Some(key)) if !failed() => {
+                    cache.store(key, dir, main_tex)
+                }
+                (Err(_), _, _) => cancel.store(true, Ordering::SeqCst),
+                _ => {}
+            }
+            result
+        };
+        let (old_main_tex, new_main_tex) = match self.config.parallel {
+            true => thread::scope(|s| {
+                let old = s.spawn(|| expand(old_tex, "old", old_key, &old_dir));
+                let new = s.spawn(|| expand(new_tex, "new", new_key, &new_dir));
+                (old.join().unwrap(), new.join().unwrap())
+            }),
+            false => (
+                expand(old_tex, "old", old_key, &old_dir),
+                expand(new_tex, "new", new_key, &new_dir),
+            ),
+        };
+        let (old_main_tex, new_main_tex) = match (old_main_tex, new_main_tex) {
+            (Ok(old), Ok(new)) => (old, new),
+            (Err(err), Ok(_)) | (Ok(_), Err(err)) => return Err(err),
+            // report the failure rather than the cancellation it caused
+            (Err(old), Err(new)) => match old.kind() {
+                ErrorKind::Cancelled => return Err(new),
+                _ => return Err(old),
+            },
+        };
+        self.progress
+            .emit(ProgressEvent::StageFinished(Stage::Expand));
 
         // diff two flatten files
-        info!(
-            "{}",
-            "Stage[3/4] Differing Two Flattened TeX file"
-                .yellow()
-                .bold()
-                .underlined()
-        );
+        self.progress.emit(ProgressEvent::StageStarted(Stage::Diff));
         let mut diff_tex = new_main_tex.clone().parent().unwrap().to_path_buf();
         diff_tex.push("diff.tex");
-        LaTeX::diff(&self.config, &old_main_tex, &new_main_tex, &diff_tex);
+        // the diff result does not exist yet, latexdiff runs aside the new main tex
+        let diff_config = |main_tex: &Path| {
+            ConfigBuilder::new()
+                .project_dir(new_dir.clone())
+                .main_tex(main_tex.to_path_buf())
+                .engine(self.config.diff_engine)
+                .bib_backend(self.config.bib_backend)
+                .max_passes(self.config.max_passes)
+                .version("diff")
+                .abort_if_error(self.config.abort_policy != AbortPolicy::Continue)
+                .capture(self.config.capture)
+                .log_dir(self.log_dir())
+                .tools(self.config.tools.clone())
+                .engine_args(self.config.diff_compile_args.clone())
+                .bib_args(self.config.bib_args.clone())
+                .latexdiff_args(self.config.latexdiff_args.clone())
+                .cancel(self.cancel.clone())
+                .progress(self.progress.clone())
+                .failures(failures.clone())
+                .build()
+        };
+        LaTeX::new(diff_config(&new_main_tex)?).diff(&old_main_tex, &new_main_tex, &diff_tex)?;
+        self.progress
+            .emit(ProgressEvent::StageFinished(Stage::Diff));
 
         // building stage
-        info!(
-            "{}",
-            "Stage[4/4] Compiling Diff Result TeX file"
-                .yellow()
-                .bold()
-                .underlined()
-        );
+        self.progress
+            .emit(ProgressEvent::StageStarted(Stage::Compile));
 
-        let tex = LaTeX::new(
-            ConfigBuilder::new()
-                .project_dir(new_dir.clone())
-                .main_tex(diff_tex.clone())
-                .build()?,
-        );
+        let tex = LaTeX::new(diff_config(&diff_tex)?);
 
-        tex.pdflatex(None)? // Run pdflatex to generate aux file
-            .pdflatex(None)?
-            .pdflatex(None)?;
+        match self.config.builder {
+            Builder::Native => {
+                // the bbl of biber is not inlined, so the diff needs one of its own
+                if tex.bib_backend() == Some(BibBackend::Biber) {
+                    tex.compile(None)?.bibliography()?;
+                }
+                tex.compile_until_stable()?;
+            }
+            Builder::Latexmk => {
+                tex.latexmk(None)?;
+            }
+        }
 
         let mut diff_pdf = tex.config.main_tex;
         diff_pdf.set_extension("pdf");
 
-        fs::copy(diff_pdf, &self.config.output)
-            .map_err(|_| Error::new(ErrorKind::UncategorizedError))?;
-        info!("Diff result placed in {}", &self.config.output.display());
+        let output = self.output(old_ver, new_ver);
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| Error::new(ErrorKind::IoError(parent.to_path_buf(), e.to_string())))?;
+        }
+        fs::copy(&diff_pdf, &output).map_err(|e| {
+            // the diff build may have failed without producing the pdf
+            let path = match diff_pdf.exists() {
+                true => output.clone(),
+                false => diff_pdf.clone(),
+            };
+            Error::new(ErrorKind::IoError(path, e.to_string()))
+        })?;
+        info!("Diff result placed in {}", output.display());
+        self.progress
+            .emit(ProgressEvent::ArtifactWritten(output.clone()));
+        self.progress
+            .emit(ProgressEvent::StageFinished(Stage::Compile));
 
-        self.abort(Ok(()));
+        let failures = failures.lock().unwrap().clone();
+        if !failures.is_empty() {
+            warn!(
+                "{}",
+                format!("The diff may be broken, failed: {}", failures.join(", "))
+                    .yellow()
+                    .bold()
+            );
+        }
+        Ok(DiffOutcome {
+            artifacts: DiffArtifacts {
+                old: old_ver,
+                new: new_ver,
+                pdf: output,
+                tex: fs::read_to_string(&diff_tex).unwrap_or_default(),
+            },
+            failures,
+        })
     }
 
-    fn prepare(&self) {
-        // check the tmp dir existence
-        let mut tmp_dir = self.config.tmp_dir.clone();
-        tmp_dir.push("old");
-        fs::create_dir_all(tmp_dir.as_path()).unwrap();
-        tmp_dir.pop();
+    /// Config of building the project of `version` in `dir`
+    fn latex_config(
+        &self,
+        version: &str,
+        dir: PathBuf,
+        main_tex: Option<PathBuf>,
+        engine: Engine,
+    ) -> ConfigBuilder {
+        let tmp_parent = self.tmp_parent_in(&dir);
+        let mut builder = ConfigBuilder::new().project_dir(dir).tmp_parent(tmp_parent);
+        if let Some(main_tex) = main_tex {
+            builder = builder.main_tex(main_tex);
+        }
+        builder
+            .engine(engine)
+            .bib_backend(self.config.bib_backend)
+            .expander(self.config.expander)
+            .version(version)
+            .abort_if_error(self.config.abort_policy == AbortPolicy::Any)
+            .capture(self.config.capture)
+            .log_dir(self.log_dir())
+            .tools(self.config.tools.clone())
+            .progress(self.progress.clone())
+            .engine_args(self.config.engine_args.clone())
+            .bib_args(self.config.bib_args.clone())
+            .expand_args(self.config.expand_args.clone())
+    }
 
-        tmp_dir.push("new");
-        fs::create_dir_all(tmp_dir.as_path()).unwrap();
+    /// Where the tmp dirs would be in the checkout `dir`, `None` if they are not
+    /// placed in the worktree of the repo
+    fn tmp_parent_in(&self, dir: &Path) -> Option<PathBuf> {
+        let workdir = fs::canonicalize(self.repo.workdir()?).ok()?;
+        let parent = fs::canonicalize(self.config.tmp_dir.parent()?).ok()?;
+        let relative = parent.strip_prefix(workdir).ok()?;
+        Some(dir.join(relative))
     }
 
-    pub fn abort(&mut self, err: std::result::Result<(), Error>) -> ! {
-        // logging
-        match err {
-            Ok(_) => {}
-            Err(e) => {
-                error!("{}", e);
+    /// Compile the project and flatten its main TeX in place,
+    /// return the path of the flattened main TeX
+    fn expand(tex: ConfigBuilder, builder: Builder) -> std::result::Result<PathBuf, Error> {
+        let tex = LaTeX::new(tex.build()?);
+
+        match builder {
+            Builder::Native => {
+                tex.compile(None)? // Run engine to generate aux file
+                    .bibliography()?;
+            }
+            Builder::Latexmk => {
+                tex.latexmk(None)?; // latexmk also takes care of the bibliography
             }
         }
-        // check dangerous operation
-        let root = PathBuf::from("/");
-        if self.config.tmp_dir == root {
-            exit(1);
+        tex.expand(None, None, None)?;
+
+        Ok(tex.config.main_tex)
+    }
+
+    /// Key of the cached build of `version`, `None` if the cache is disabled
+    /// or the version is not a tree, e.g. the working tree
+    fn cache_key(
+        &self,
+        git: &Git,
+        version: CommitWrapper,
+        main_tex: &Option<PathBuf>,
+        engine: Engine,
+    ) -> Option<Oid> {
+        self.config.cache_dir.as_ref()?;
+        let tree = git.tree_id(version)?;
+        let options = format!(
+            "main_tex={:?}\nengine={:?}\nbuilder={:?}\nbib_backend={:?}\nexpander={:?}\n\
+             engine_args={:?}\nbib_args={:?}\nexpand_args={:?}\ntools:\n{}",
+            main_tex,
+            engine,
+            self.config.builder,
+            self.config.bib_backend,
+            self.config.expander,
+            self.config.engine_args,
+            self.config.bib_args,
+            self.config.expand_args,
+            self.config.tools.fingerprint(),
+        );
+        Some(Cache::key(tree, &options))
+    }
+
+    /// Main tex of both versions relative to the checkouts, follow the
+    /// renames if the main tex given by user is missing in one of them
+    fn locate_main_tex(
+        &self,
+        git: &Git,
+        old_ver: CommitWrapper,
+        new_ver: CommitWrapper,
+    ) -> (Option<PathBuf>, Option<PathBuf>) {
+        let old_dir = self.config.tmp_dir.join("old");
+        let new_dir = self.config.tmp_dir.join("new");
+        let mut old_main_tex = self.config.old_main_tex.clone();
+        let mut new_main_tex = self.config.new_main_tex.clone();
+
+        let old_missing = matches!(&old_main_tex, Some(path) if !old_dir.join(path).is_file());
+        let new_missing = matches!(&new_main_tex, Some(path) if !new_dir.join(path).is_file());
+        if !old_missing && !new_missing {
+            return (old_main_tex, new_main_tex);
+        }
+
+        let renames = git.renames(old_ver, new_ver);
+        if old_missing {
+            let path = old_main_tex.unwrap();
+            old_main_tex = match renames.iter().find(|(_, new)| new == &path) {
+                Some((old, _)) => {
+                    info!("Main TeX was {} in the old version", old.display());
+                    Some(old.clone())
+                }
+                None => Some(path),
+            };
+        }
+        if new_missing {
+            let path = new_main_tex.unwrap();
+            new_main_tex = match renames.iter().find(|(old, _)| old == &path) {
+                Some((_, new)) => {
+                    info!(
+                        "Main TeX is renamed to {} in the new version",
+                        new.display()
+                    );
+                    Some(new.clone())
+                }
+                None => Some(path),
+            };
         }
-        // remove the tmp dir
-        if !self.config.no_clean {
-            fs::remove_dir_all(&self.config.tmp_dir).unwrap();
+
+        (old_main_tex, new_main_tex)
+    }
+
+    /// Path of the diff result, with `{old}`, `{new}` and `{date}` in
+    /// the configured output replaced by the versions and today
+    pub fn output(&self, old: CommitWrapper, new: CommitWrapper) -> PathBuf {
+        let output = self.config.output.to_string_lossy();
+        let output = output
+            .replace("{old}", &old.to_string())
+            .replace("{new}", &new.to_string())
+            .replace("{date}", &Local::now().format("%Y-%m-%d").to_string());
+        PathBuf::from(output)
+    }
+
+    /// Directory to place the output of tools, see [`Capture::File`]
+    fn log_dir(&self) -> PathBuf {
+        self.config.tmp_dir.join("logs")
+    }
+
+    fn prepare(&mut self) -> std::result::Result<(), Error> {
+        // clean up the previous run first, which may share the tmp dir
+        drop(self.tmp_dir.take());
+        let tmp_dir = TmpDir::create(self.config.tmp_dir.clone()).map_err(|e| {
+            error!(
+                "Can not create the tmp dir {}: {}",
+                self.config.tmp_dir.display(),
+                e
+            );
+            Error::new(ErrorKind::InvalidTmpDir(self.config.tmp_dir.clone()))
+        })?;
+        if let Err(e) = tmp_dir.exclude_from(&self.repo) {
+            warn!("Fail to exclude the tmp dir from git: {}", e);
+        }
+        self.tmp_dir = Some(
+            tmp_dir
+                .keep(self.config.no_clean)
+                // keep the logs for debugging
+                .keep_logs(self.config.capture == Capture::File),
+        );
+        Ok(())
+    }
+
+    fn check_cancel(&self) -> std::result::Result<(), Error> {
+        match self.cancel.load(Ordering::SeqCst) {
+            true => Err(Error::new(ErrorKind::Cancelled)),
+            false => Ok(()),
         }
-        exit(0);
     }
 }
@@ -1,142 +1,443 @@
 use crate::error::{Error, ErrorKind};
-use git2::{Oid, Repository};
-
+use crate::item::Item;
+use crate::wrapper::CommitWrapper;
+use clap::ValueEnum;
 use crossterm::style::Stylize;
+use git2::{ErrorCode, Repository};
+use serde::Deserialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
 
-#[cfg(not(windows))]
+#[cfg(all(feature = "skim", not(windows)))]
 use skim::prelude::*;
 
-#[cfg(not(windows))]
-use crate::item::Item;
-
-#[cfg(windows)]
-pub struct SelectorBuilder {
-    fzf_opts: (),
+/// Backend of the interactive selection of versions
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SelectorKind {
+    /// The built-in fuzzy finder, only if built with the `skim` feature
+    Skim,
+    /// The external `fzf` binary
+    Fzf,
+    /// No interactive selection, `--old` and `--new` are required
+    None,
 }
 
-#[cfg(windows)]
-pub struct Selector {
-    fzf_opts: (),
+impl Default for SelectorKind {
+    fn default() -> Self {
+        match cfg!(all(feature = "skim", not(windows))) {
+            true => SelectorKind::Skim,
+            false => SelectorKind::Fzf,
+        }
+    }
 }
 
-#[cfg(not(windows))]
-pub struct SelectorBuilder {
-    repo: Option<Arc<Repository>>,
-    skim_opts: SkimOptions<'static>,
-}
+/// Pick the versions interactively
+pub trait Selector {
+    fn repo(&self) -> &Arc<Repository>;
 
-#[cfg(not(windows))]
-pub struct Selector {
-    repo: Arc<Repository>,
-    skim_opts: SkimOptions<'static>,
+    /// Let the user pick at most `count` versions, with `header` telling what to pick
+    fn pick(&self, header: &str, count: usize) -> std::result::Result<Vec<CommitWrapper>, Error>;
+
+    /// Pick exactly `count` versions, ask again until the user does
+    fn pick_exactly(
+        &self,
+        header: &str,
+        count: usize,
+    ) -> std::result::Result<Vec<CommitWrapper>, Error> {
+        loop {
+            let picked = self.pick(header, count)?;
+            if picked.len() == count {
+                for wrapper in &picked {
+                    let item = Item {
+                        repo: self.repo().clone(),
+                        wrapper: *wrapper,
+                    };
+                    println!("{}", item.output().green());
+                }
+                return Ok(picked);
+            }
+            println!(
+                "{}",
+                format!(
+                    "Select exactly {} item(s), but {} selected, please try again",
+                    count,
+                    picked.len()
+                )
+                .red()
+            );
+        }
+    }
+
+    /// Pick one version, `header` tells which one, e.g. `select OLD`
+    fn select(&self, header: &str) -> std::result::Result<CommitWrapper, Error> {
+        Ok(self.pick_exactly(header, 1)?[0])
+    }
+
+    /// Pick both versions in one session, the older one comes first
+    fn select_pair(&self) -> std::result::Result<(CommitWrapper, CommitWrapper), Error> {
+        let picked =
+            self.pick_exactly("select OLD and NEW: TAB to mark both, ENTER to confirm", 2)?;
+        Ok(order(self.repo(), picked[0], picked[1]))
+    }
 }
 
-#[cfg(windows)]
-impl SelectorBuilder {
-    pub fn build(self) -> Selector {
-        Selector {
-            fzf_opts: self.fzf_opts,
+/// `(older, newer)` of two versions, commits are ordered by the time of committing,
+/// the uncommitted changes are newer than any commit, and the worktree is newer than the index
+pub fn order(
+    repo: &Repository,
+    a: CommitWrapper,
+    b: CommitWrapper,
+) -> (CommitWrapper, CommitWrapper) {
+    let age = |wrapper: CommitWrapper| match wrapper {
+        CommitWrapper::Commit(oid) => (
+            0,
+            repo.find_commit(oid)
+                .map_or(0, |commit| commit.time().seconds()),
+        ),
+        CommitWrapper::Index => (1, 0),
+        CommitWrapper::Worktree => (2, 0),
+    };
+    let newer = match (age(a).cmp(&age(b)), a, b) {
+        (std::cmp::Ordering::Equal, CommitWrapper::Commit(a), CommitWrapper::Commit(b)) => {
+            // committed in the same second, the descendant is newer
+            repo.graph_descendant_of(a, b).unwrap_or(false)
         }
+        (ordering, _, _) => ordering == std::cmp::Ordering::Greater,
+    };
+    match newer {
+        true => (b, a),
+        false => (a, b),
     }
 }
 
-#[cfg(not(windows))]
+pub struct SelectorBuilder {
+    repo: Option<Arc<Repository>>,
+    kind: SelectorKind,
+    fzf: PathBuf,
+}
+
 impl SelectorBuilder {
-    pub fn skim_opts(mut self, skim_opts: SkimOptions<'static>) -> SelectorBuilder {
-        self.skim_opts = skim_opts;
+    pub fn repo(mut self, repo: Arc<Repository>) -> SelectorBuilder {
+        self.repo = Some(repo);
         self
     }
 
-    pub fn repo(mut self, repo: Arc<Repository>) -> SelectorBuilder {
-        self.repo = Some(repo);
+    pub fn kind(mut self, kind: SelectorKind) -> SelectorBuilder {
+        self.kind = kind;
         self
     }
 
-    pub fn build(self) -> Selector {
-        Selector {
-            repo: self.repo.unwrap(),
-            skim_opts: self.skim_opts,
+    /// Path of the fzf binary, found in $PATH by default
+    pub fn fzf(mut self, path: PathBuf) -> SelectorBuilder {
+        self.fzf = path;
+        self
+    }
+
+    pub fn build(self) -> std::result::Result<Box<dyn Selector>, Error> {
+        let repo = self.repo.unwrap();
+        match self.kind {
+            #[cfg(all(feature = "skim", not(windows)))]
+            SelectorKind::Skim => Ok(Box::new(SkimSelector { repo })),
+            #[cfg(not(all(feature = "skim", not(windows))))]
+            SelectorKind::Skim => Err(Error::new(ErrorKind::SelectorNotAvailable(String::from(
+                "skim",
+            )))),
+            SelectorKind::Fzf => {
+                let fzf = which::which(&self.fzf).map_err(|_| {
+                    Error::new(ErrorKind::BinaryNotFound(self.fzf.display().to_string()))
+                })?;
+                Ok(Box::new(FzfSelector { repo, fzf }))
+            }
+            SelectorKind::None => Err(Error::new(ErrorKind::SelectorNotAvailable(String::from(
+                "none",
+            )))),
         }
     }
 }
 
 impl Default for SelectorBuilder {
-    #[cfg(windows)]
-    fn default() -> Self {
-        SelectorBuilder { fzf_opts: () }
-    }
-
-    #[cfg(not(windows))]
     fn default() -> Self {
         SelectorBuilder {
             repo: None,
-            skim_opts: SkimOptionsBuilder::default()
-                .reverse(true)
-                .multi(false)
-                .preview(Some("")) // preview should be specified to enable preview window
-                // .height(Some("50%")) // FIXME: if height is not 100%. it will be buggy
-                // See https://github.com/lotabout/skim/issues/494
-                .build()
-                .unwrap(),
+            kind: SelectorKind::default(),
+            fzf: PathBuf::from("fzf"),
         }
     }
 }
 
-#[cfg(windows)]
-impl Selector {
-    pub fn select(&self) -> std::result::Result<Oid, Error> {
-        // TODO: use fzf instead on windows target
-        return Err(Error::new(ErrorKind::NotSupportedDevice));
+/// Uncommitted changes first, then the commits from HEAD
+fn items(repo: &Arc<Repository>) -> std::result::Result<Vec<Item>, Error> {
+    let git_error = |e: git2::Error| {
+        Error::new(ErrorKind::IoError(
+            repo.path().to_path_buf(),
+            e.message().to_string(),
+        ))
+    };
+    let mut versions = vec![CommitWrapper::Worktree, CommitWrapper::Index];
+    // nothing is committed yet, only the uncommitted changes can be picked
+    let unborn = matches!(repo.head(), Err(e) if e.code() == ErrorCode::UnbornBranch);
+    if !unborn {
+        let mut walk = repo.revwalk().map_err(git_error)?;
+        walk.push_head().map_err(git_error)?;
+        for oid in walk {
+            versions.push(CommitWrapper::Commit(oid.map_err(git_error)?));
+        }
     }
+
+    Ok(versions
+        .into_iter()
+        .map(|wrapper| Item {
+            repo: repo.clone(),
+            wrapper,
+        })
+        .collect())
+}
+
+/// Preview of `version` in the repo containing `repo_dir`, which fzf shows by running
+/// ourselves with the hidden `--preview-item`
+pub fn preview(repo_dir: &Path, version: &str) -> std::result::Result<String, Error> {
+    let repo = Repository::discover(repo_dir)
+        .map_err(|_| Error::new(ErrorKind::RepoNotFound(repo_dir.to_path_buf())))?;
+    // See: https://github.com/rust-lang/git2-rs/issues/194
+    #[allow(clippy::arc_with_non_send_sync)]
+    let repo = Arc::new(repo);
+    let wrapper = CommitWrapper::parse(&repo, version)?;
+    Ok(Item { repo, wrapper }.preview())
+}
+
+#[cfg(all(feature = "skim", not(windows)))]
+struct SkimSelector {
+    repo: Arc<Repository>,
 }
 
-#[cfg(not(windows))]
-impl Selector {
-    fn start(&self) -> SkimOutput {
+#[cfg(all(feature = "skim", not(windows)))]
+impl Selector for SkimSelector {
+    fn repo(&self) -> &Arc<Repository> {
+        &self.repo
+    }
+
+    fn pick(&self, header: &str, count: usize) -> std::result::Result<Vec<CommitWrapper>, Error> {
+        let options = SkimOptionsBuilder::default()
+            .reverse(true)
+            .multi(count > 1)
+            .header(Some(header))
+            .preview(Some("")) // preview should be specified to enable preview window
+            // .height(Some("50%")) // FIXME: if height is not 100%. it will be buggy
+            // See https://github.com/lotabout/skim/issues/494
+            .build()
+            .unwrap();
+
         // Init Channel
         let (tx, rx): (SkimItemSender, SkimItemReceiver) = unbounded();
 
-        // Get Commits Walker, from HEAD by default
-        let mut walk = self.repo.revwalk().unwrap();
-        walk.push_head().unwrap();
-
-        for oid in walk {
-            let _ = tx.send(Arc::from(Item {
-                repo: self.repo.clone(),
-                oid: oid.unwrap(),
-            }));
+        for item in items(&self.repo)? {
+            let _ = tx.send(Arc::new(item));
         }
 
         drop(tx); // Notify Skim
 
-        Skim::run_with(&self.skim_opts, Some(rx)).unwrap()
-    }
-
-    fn parse(out: SkimOutput) -> std::result::Result<Oid, Error> {
+        let out = Skim::run_with(&options, Some(rx)).unwrap();
         if out.is_abort {
             return Err(Error::new(ErrorKind::SkimAbort));
         }
 
-        let mut selected_item = out.selected_items;
+        Ok(out
+            .selected_items
+            .iter()
+            .map(|item| (**item).as_any().downcast_ref::<Item>().unwrap().wrapper)
+            .collect())
+    }
+}
+
+/// Run the external fzf, each line is `<version>\t<line of the item>`
+/// and only the latter is shown
+struct FzfSelector {
+    repo: Arc<Repository>,
+    fzf: PathBuf,
+}
+
+impl FzfSelector {
+    /// Command of fzf to preview the line, `{1}` is the version
+    fn preview_command(&self) -> std::result::Result<String, Error> {
+        // the running executable may be gone, e.g. replaced by an upgrade
+        let exe = std::env::current_exe()
+            .ok()
+            .filter(|exe| exe.is_file())
+            .or_else(|| which::which("git-latexdiff").ok())
+            .ok_or_else(|| Error::new(ErrorKind::BinaryNotFound(String::from("git-latexdiff"))))?;
+        let repo_dir = self.repo.workdir().unwrap_or(self.repo.path());
+        Ok(format!(
+            "{} --repo-dir {} --preview-item {{1}}",
+            quote(&exe.display().to_string()),
+            quote(&repo_dir.display().to_string())
+        ))
+    }
+}
+
+impl Selector for FzfSelector {
+    fn repo(&self) -> &Arc<Repository> {
+        &self.repo
+    }
+
+    fn pick(&self, header: &str, count: usize) -> std::result::Result<Vec<CommitWrapper>, Error> {
+        let multi = match count {
+            1 => String::from("--no-multi"),
+            _ => format!("--multi={}", count),
+        };
+        let items = items(&self.repo)?;
+        let mut child = Command::new(&self.fzf)
+            .arg("--ansi")
+            .arg("--reverse")
+            .arg(multi)
+            .arg("--header")
+            .arg(header)
+            .arg("--delimiter=\t")
+            .arg("--with-nth=2..")
+            .arg("--preview")
+            .arg(self.preview_command()?)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|_| Error::new(ErrorKind::BinaryNotFound(self.fzf.display().to_string())))?;
+
+        let mut stdin = child.stdin.take().unwrap();
+        for item in items {
+            // fzf quits before reading everything if a line is picked early
+            let line = format!("{}\t{}", item.wrapper.key(), item.line());
+            if writeln!(stdin, "{}", line).is_err() {
+                break;
+            }
+        }
+        drop(stdin); // Notify fzf
+
+        let out = child
+            .wait_with_output()
+            .map_err(|_| Error::new(ErrorKind::UncategorizedError))?;
+        match out.status.code() {
+            Some(0) => {}
+            // nothing matches
+            Some(1) => return Ok(Vec::new()),
+            // 130 if aborted
+            _ => return Err(Error::new(ErrorKind::SkimAbort)),
+        }
+
+        String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .filter_map(|line| line.split('\t').next())
+            .map(|version| CommitWrapper::parse(&self.repo, version.trim()))
+            .collect()
+    }
+}
 
-        // TODO: Error Handling
-        if selected_item.len() > 1 {
-            println!("{}", "More than one items are selected".red());
-            unreachable!();
-        } else if selected_item.len() <= 0 {
-            println!("{}", "No item is selected".red());
-            unreachable!();
+/// Quote `arg` for the shell that fzf runs the preview command in
+fn quote(arg: &str) -> String {
+    match cfg!(windows) {
+        true => format!("\"{}\"", arg),
+        false => format!("'{}'", arg.replace('\'', r"'\''")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{Oid, Signature, Time};
+    use tempfile::TempDir;
+
+    /// Commit on top of `parents` at `seconds` since the epoch, on no branch
+    fn commit(repo: &Repository, seconds: i64, parents: &[Oid]) -> CommitWrapper {
+        let signature = Signature::new("test", "test@example.com", &Time::new(seconds, 0)).unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let parents: Vec<_> = parents
+            .iter()
+            .map(|oid| repo.find_commit(*oid).unwrap())
+            .collect();
+        let parents: Vec<_> = parents.iter().collect();
+        let message = format!("commit at {}", seconds);
+        let oid = repo
+            .commit(None, &signature, &signature, &message, &tree, &parents)
+            .unwrap();
+        CommitWrapper::Commit(oid)
+    }
+
+    fn oid(wrapper: CommitWrapper) -> Oid {
+        match wrapper {
+            CommitWrapper::Commit(oid) => oid,
+            _ => unreachable!(),
         }
+    }
 
-        let item = selected_item.pop().unwrap();
-        println!("{}", item.output().green());
-        let item = (*item).as_any().downcast_ref::<Item>().unwrap();
+    #[test]
+    fn older_commit_comes_first() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let old = commit(&repo, 1_000, &[]);
+        let new = commit(&repo, 2_000, &[oid(old)]);
 
-        return Ok(item.oid);
+        assert_eq!(order(&repo, new, old), (old, new));
+        assert_eq!(order(&repo, old, new), (old, new));
     }
 
-    pub fn select(&self) -> std::result::Result<Oid, Error> {
-        let skim_out = self.start();
-        return Selector::parse(skim_out);
+    #[test]
+    fn commit_time_wins_over_ancestry() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let root = commit(&repo, 1_000, &[]);
+        // neither descends from the other, only the time counts
+        let left = commit(&repo, 3_000, &[oid(root)]);
+        let right = commit(&repo, 2_000, &[oid(root)]);
+
+        assert_eq!(order(&repo, left, right), (right, left));
+        assert_eq!(order(&repo, right, left), (right, left));
+    }
+
+    #[test]
+    fn descendant_is_newer_in_the_same_second() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let old = commit(&repo, 1_000, &[]);
+        let new = commit(&repo, 1_000, &[oid(old)]);
+
+        assert_eq!(order(&repo, new, old), (old, new));
+        assert_eq!(order(&repo, old, new), (old, new));
+    }
+
+    #[test]
+    fn items_of_repo_without_commits() {
+        let dir = TempDir::new().unwrap();
+        // See: https://github.com/rust-lang/git2-rs/issues/194
+        #[allow(clippy::arc_with_non_send_sync)]
+        let repo = Arc::new(Repository::init(dir.path()).unwrap());
+        let versions = |repo: &Arc<Repository>| -> Vec<CommitWrapper> {
+            let items = items(repo).unwrap();
+            for item in &items {
+                item.preview();
+            }
+            items.iter().map(|item| item.wrapper).collect()
+        };
+        let (index, worktree) = (CommitWrapper::Index, CommitWrapper::Worktree);
+
+        assert_eq!(versions(&repo), vec![worktree, index]);
+        let head = commit(&repo, 1_000, &[]);
+        repo.set_head_detached(oid(head)).unwrap();
+        assert_eq!(versions(&repo), vec![worktree, index, head]);
+    }
+
+    #[test]
+    fn uncommitted_changes_are_newest() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let head = commit(&repo, 1_000, &[]);
+        let (index, worktree) = (CommitWrapper::Index, CommitWrapper::Worktree);
+
+        assert_eq!(order(&repo, worktree, index), (index, worktree));
+        assert_eq!(order(&repo, index, worktree), (index, worktree));
+        assert_eq!(order(&repo, index, head), (head, index));
+        assert_eq!(order(&repo, worktree, head), (head, worktree));
+        assert_eq!(order(&repo, head, worktree), (head, worktree));
     }
 }
@@ -0,0 +1,453 @@
+use crate::args::Args;
+use crate::error::{Error, ErrorKind};
+use crate::latex::{BibBackend, Builder, Capture, Engine, Expander};
+use crate::selector::SelectorKind;
+use crate::tools::{Tool, ToolName};
+use clap::ValueEnum;
+use git2::{ConfigLevel, Repository};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the repo level config file, placed in the root of the worktree
+pub const REPO_FILE: &str = ".git-latexdiff.toml";
+
+/// Settings read from the config files, every field mirrors the CLI flag of the same name.
+///
+/// ```toml
+/// main-tex = "paper.tex"
+/// engine = "xelatex"
+/// output = "diffs/{old}-{new}.pdf"
+///
+/// [profiles.review]
+/// latexdiff-args = ["--type=CFONT"]
+/// ```
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Settings {
+    pub main_tex: Option<PathBuf>,
+    pub old_main_tex: Option<PathBuf>,
+    pub new_main_tex: Option<PathBuf>,
+    pub engine: Option<Engine>,
+    pub old_engine: Option<Engine>,
+    pub new_engine: Option<Engine>,
+    pub diff_engine: Option<Engine>,
+    pub builder: Option<Builder>,
+    pub bib_backend: Option<BibBackend>,
+    pub expander: Option<Expander>,
+    pub capture: Option<Capture>,
+    pub selector: Option<SelectorKind>,
+    /// Supports the `{old}`, `{new}` and `{date}` placeholders
+    pub output: Option<PathBuf>,
+    pub latexdiff_path: Option<PathBuf>,
+    pub latexdiff_args: Option<Vec<String>>,
+    pub engine_args: Option<Vec<String>>,
+    pub bib_args: Option<Vec<String>>,
+    pub expand_args: Option<Vec<String>>,
+    pub diff_compile_args: Option<Vec<String>>,
+    /// Path, args and environment of the tools, e.g. `[tools.pdflatex]`
+    pub tools: HashMap<ToolName, Tool>,
+    /// Named sets of settings, which override the settings above once selected by `--profile`
+    pub profiles: HashMap<String, Settings>,
+}
+
+impl Settings {
+    /// Merge the settings of the repo containing `repo_dir`, in the order of increasing precedence:
+    /// the user level config file, the system and global git config, the repo level
+    /// config file, and the git config of the repo
+    pub fn load(repo_dir: &Path, profile: Option<&str>) -> Result<Settings, Error> {
+        let mut layers = Vec::new();
+
+        if let Some(path) = Settings::user_file() {
+            if let Some(settings) = Settings::read(&path)? {
+                layers.push(settings);
+            }
+        }
+
+        if let Ok(repo) = Repository::discover(repo_dir) {
+            // the git config outside of the repo is as personal as the user level config file
+            for level in [
+                ConfigLevel::ProgramData,
+                ConfigLevel::System,
+                ConfigLevel::XDG,
+                ConfigLevel::Global,
+            ] {
+                layers.push(Settings::from_git_config(&repo, level)?);
+            }
+            if let Some(root) = repo.workdir() {
+                if let Some(settings) = Settings::read(&root.join(REPO_FILE))? {
+                    // paths in the repo file are relative to the root of the repo
+                    layers.push(settings.relative_to(root));
+                }
+            }
+            layers.push(Settings::from_git_config(&repo, ConfigLevel::Local)?);
+        }
+
+        if let Some(profile) = profile {
+            if !layers
+                .iter()
+                .any(|layer| layer.profiles.contains_key(profile))
+            {
+                return Err(Error::new(ErrorKind::ProfileNotFound(String::from(
+                    profile,
+                ))));
+            }
+            debug!("Using the profile {}", profile);
+        }
+
+        Ok(layers
+            .into_iter()
+            .map(|layer| layer.with_profile(profile))
+            .fold(Settings::default(), |lower, upper| upper.or(lower)))
+    }
+
+    /// `$XDG_CONFIG_HOME/git-latexdiff/config.toml` or the equivalent of the platform
+    fn user_file() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("git-latexdiff").join("config.toml"))
+    }
+
+    /// `None` if the file does not exist
+    fn read(path: &Path) -> Result<Option<Settings>, Error> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) => return Ok(None),
+        };
+        debug!("Loading settings from {}", path.display());
+        toml::from_str(&content).map(Some).map_err(|e| {
+            Error::new(ErrorKind::InvalidSettings(
+                path.display().to_string(),
+                e.to_string(),
+            ))
+        })
+    }
+
+    /// `latexdiff.*` keys of the git config at `level`, which is how the original
+    /// shell `git-latexdiff` is configured
+    fn from_git_config(repo: &Repository, level: ConfigLevel) -> Result<Settings, Error> {
+        let config = match repo.config().and_then(|config| config.open_level(level)) {
+            Ok(config) => config,
+            Err(_) => return Ok(Settings::default()),
+        };
+        let invalid = |key: &str, reason: String| {
+            Error::new(ErrorKind::InvalidSettings(
+                format!("{} of git config", key),
+                reason,
+            ))
+        };
+
+        let engine = match config.get_string("latexdiff.engine") {
+            Ok(engine) => {
+                Some(Engine::from_str(&engine, true).map_err(|e| invalid("latexdiff.engine", e))?)
+            }
+            Err(_) => None,
+        };
+        // quoted as in the shell, e.g. `--exclude-textcmd="section,subsection"`
+        let latexdiff_args = match config.get_string("latexdiff.args") {
+            Ok(args) => Some(
+                shell_words::split(&args).map_err(|e| invalid("latexdiff.args", e.to_string()))?,
+            ),
+            Err(_) => None,
+        };
+
+        let settings = Settings {
+            main_tex: config.get_path("latexdiff.main").ok(),
+            engine,
+            output: config.get_path("latexdiff.output").ok(),
+            latexdiff_args,
+            ..Settings::default()
+        };
+        // paths are relative to the root of the repo, as in the config file
+        Ok(match repo.workdir() {
+            Some(root) => settings.relative_to(root),
+            None => settings,
+        })
+    }
+
+    /// Settings of `profile` on top of the others
+    fn with_profile(mut self, profile: Option<&str>) -> Settings {
+        match profile.and_then(|profile| self.profiles.remove(profile)) {
+            Some(overrides) => overrides.or(self),
+            None => self,
+        }
+    }
+
+    /// Fill the missing fields with those of `lower`
+    fn or(self, lower: Settings) -> Settings {
+        Settings {
+            main_tex: self.main_tex.or(lower.main_tex),
+            old_main_tex: self.old_main_tex.or(lower.old_main_tex),
+            new_main_tex: self.new_main_tex.or(lower.new_main_tex),
+            engine: self.engine.or(lower.engine),
+            old_engine: self.old_engine.or(lower.old_engine),
+            new_engine: self.new_engine.or(lower.new_engine),
+            diff_engine: self.diff_engine.or(lower.diff_engine),
+            builder: self.builder.or(lower.builder),
+            bib_backend: self.bib_backend.or(lower.bib_backend),
+            expander: self.expander.or(lower.expander),
+            capture: self.capture.or(lower.capture),
+            selector: self.selector.or(lower.selector),
+            output: self.output.or(lower.output),
+            latexdiff_path: self.latexdiff_path.or(lower.latexdiff_path),
+            latexdiff_args: self.latexdiff_args.or(lower.latexdiff_args),
+            engine_args: self.engine_args.or(lower.engine_args),
+            bib_args: self.bib_args.or(lower.bib_args),
+            expand_args: self.expand_args.or(lower.expand_args),
+            diff_compile_args: self.diff_compile_args.or(lower.diff_compile_args),
+            tools: merge_tools(self.tools, lower.tools),
+            profiles: HashMap::new(),
+        }
+    }
+
+    fn relative_to(mut self, root: &Path) -> Settings {
+        let resolve = |path: Option<PathBuf>| path.map(|path| root.join(path));
+        self.main_tex = resolve(self.main_tex);
+        self.old_main_tex = resolve(self.old_main_tex);
+        self.new_main_tex = resolve(self.new_main_tex);
+        self.output = resolve(self.output);
+        // bare names are still searched in $PATH
+        let resolve_tool = |path: Option<PathBuf>| match path {
+            Some(path) if path.components().count() > 1 => Some(root.join(path)),
+            path => path,
+        };
+        self.latexdiff_path = resolve_tool(self.latexdiff_path);
+        for tool in self.tools.values_mut() {
+            tool.path = resolve_tool(tool.path.take());
+        }
+        for profile in self.profiles.values_mut() {
+            *profile = std::mem::take(profile).relative_to(root);
+        }
+        self
+    }
+
+    /// Fill the args that are given neither on the command line nor by the environment
+    pub fn apply(self, args: &mut Args) {
+        args.main_tex = args.main_tex.take().or(self.main_tex);
+        args.old_main_tex = args.old_main_tex.take().or(self.old_main_tex);
+        args.new_main_tex = args.new_main_tex.take().or(self.new_main_tex);
+        args.engine = args.engine.or(self.engine);
+        args.old_engine = args.old_engine.or(self.old_engine);
+        args.new_engine = args.new_engine.or(self.new_engine);
+        args.diff_engine = args.diff_engine.or(self.diff_engine);
+        args.builder = args.builder.or(self.builder);
+        args.bib_backend = args.bib_backend.or(self.bib_backend);
+        args.expander = args.expander.or(self.expander);
+        args.capture = args.capture.or(self.capture);
+        args.selector = args.selector.or(self.selector);
+        args.output = args.output.take().or(self.output);
+        args.latexdiff_path = args.latexdiff_path.take().or(self.latexdiff_path);
+        args.tools = merge_tools(std::mem::take(&mut args.tools), self.tools);
+        fill_args(&mut args.latexdiff_args, self.latexdiff_args);
+        fill_args(&mut args.engine_args, self.engine_args);
+        fill_args(&mut args.bib_args, self.bib_args);
+        fill_args(&mut args.expand_args, self.expand_args);
+        fill_args(&mut args.diff_compile_args, self.diff_compile_args);
+    }
+}
+
+/// Args given on the command line replace those of the settings as a whole
+fn fill_args(args: &mut Vec<OsString>, settings: Option<Vec<String>>) {
+    if args.is_empty() {
+        *args = settings
+            .unwrap_or_default()
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+    }
+}
+
+/// Tools of `upper` on top of those of `lower`
+fn merge_tools(
+    mut upper: HashMap<ToolName, Tool>,
+    lower: HashMap<ToolName, Tool>,
+) -> HashMap<ToolName, Tool> {
+    for (name, tool) in lower {
+        let merged = match upper.remove(&name) {
+            Some(upper) => upper.or(tool),
+            None => tool,
+        };
+        upper.insert(name, merged);
+    }
+    upper
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use tempfile::TempDir;
+
+    /// A repo with `content` as its repo file and `engine` in its git config
+    fn repo(content: &str, engine: Option<&str>) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join(REPO_FILE), content).unwrap();
+        if let Some(engine) = engine {
+            let mut config = repo
+                .config()
+                .unwrap()
+                .open_level(ConfigLevel::Local)
+                .unwrap();
+            config.set_str("latexdiff.engine", engine).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn paths_are_relative_to_root() {
+        let settings = Settings {
+            main_tex: Some(PathBuf::from("paper.tex")),
+            latexdiff_path: Some(PathBuf::from("bin/latexdiff")),
+            tools: HashMap::from([
+                (
+                    ToolName::Pdflatex,
+                    Tool {
+                        path: Some(PathBuf::from("pdflatex")),
+                        ..Tool::default()
+                    },
+                ),
+                (
+                    ToolName::Biber,
+                    Tool {
+                        path: Some(PathBuf::from("/opt/biber")),
+                        ..Tool::default()
+                    },
+                ),
+            ]),
+            ..Settings::default()
+        }
+        .relative_to(Path::new("/repo"));
+
+        assert_eq!(settings.main_tex, Some(PathBuf::from("/repo/paper.tex")));
+        assert_eq!(
+            settings.latexdiff_path,
+            Some(PathBuf::from("/repo/bin/latexdiff"))
+        );
+        assert_eq!(
+            settings.tools[&ToolName::Pdflatex].path,
+            Some(PathBuf::from("pdflatex"))
+        );
+        assert_eq!(
+            settings.tools[&ToolName::Biber].path,
+            Some(PathBuf::from("/opt/biber"))
+        );
+    }
+
+    #[test]
+    fn upper_layer_wins() {
+        let upper = Settings {
+            engine: Some(Engine::Xelatex),
+            latexdiff_args: Some(Vec::new()),
+            tools: HashMap::from([(
+                ToolName::Pdflatex,
+                Tool {
+                    args: vec![String::from("-shell-escape")],
+                    ..Tool::default()
+                },
+            )]),
+            ..Settings::default()
+        };
+        let lower = Settings {
+            engine: Some(Engine::Lualatex),
+            builder: Some(Builder::Latexmk),
+            latexdiff_args: Some(vec![String::from("--type=CFONT")]),
+            tools: HashMap::from([(
+                ToolName::Pdflatex,
+                Tool {
+                    path: Some(PathBuf::from("/opt/pdflatex")),
+                    args: vec![String::from("-draftmode")],
+                    ..Tool::default()
+                },
+            )]),
+            ..Settings::default()
+        };
+        let settings = upper.or(lower);
+
+        assert_eq!(settings.engine, Some(Engine::Xelatex));
+        assert_eq!(settings.builder, Some(Builder::Latexmk));
+        // an empty list is still given, rather than missing
+        assert_eq!(settings.latexdiff_args, Some(Vec::new()));
+        let pdflatex = &settings.tools[&ToolName::Pdflatex];
+        assert_eq!(pdflatex.path, Some(PathBuf::from("/opt/pdflatex")));
+        assert_eq!(pdflatex.args, vec![String::from("-shell-escape")]);
+    }
+
+    #[test]
+    fn profile_overrides_its_layer() {
+        let review = Settings {
+            engine: Some(Engine::Lualatex),
+            ..Settings::default()
+        };
+        let settings = || Settings {
+            engine: Some(Engine::Xelatex),
+            builder: Some(Builder::Latexmk),
+            profiles: HashMap::from([(String::from("review"), review.clone())]),
+            ..Settings::default()
+        };
+
+        let selected = settings().with_profile(Some("review"));
+        assert_eq!(selected.engine, Some(Engine::Lualatex));
+        assert_eq!(selected.builder, Some(Builder::Latexmk));
+        assert_eq!(
+            settings().with_profile(Some("draft")).engine,
+            Some(Engine::Xelatex)
+        );
+        assert_eq!(settings().with_profile(None).engine, Some(Engine::Xelatex));
+    }
+
+    #[test]
+    fn args_override_settings() {
+        // given on the command line or by the environment
+        let mut args = Args::try_parse_from([
+            "git-latexdiff",
+            "--engine",
+            "lualatex",
+            "--latexdiff-args",
+            "--type=UNDERLINE",
+        ])
+        .unwrap();
+        Settings {
+            engine: Some(Engine::Xelatex),
+            builder: Some(Builder::Latexmk),
+            latexdiff_args: Some(vec![String::from("--type=CFONT")]),
+            engine_args: Some(vec![String::from("-shell-escape")]),
+            ..Settings::default()
+        }
+        .apply(&mut args);
+
+        assert_eq!(args.engine, Some(Engine::Lualatex));
+        assert_eq!(args.builder, Some(Builder::Latexmk));
+        assert_eq!(
+            args.latexdiff_args,
+            vec![OsString::from("--type=UNDERLINE")]
+        );
+        assert_eq!(args.engine_args, vec![OsString::from("-shell-escape")]);
+    }
+
+    #[test]
+    fn git_config_of_repo_overrides_repo_file() {
+        let dir = repo(
+            "main-tex = \"paper.tex\"\nengine = \"xelatex\"\nbuilder = \"latexmk\"\n",
+            Some("lualatex"),
+        );
+        let settings = Settings::load(dir.path(), None).unwrap();
+
+        assert_eq!(settings.engine, Some(Engine::Lualatex));
+        assert_eq!(settings.builder, Some(Builder::Latexmk));
+        assert_eq!(settings.main_tex, Some(dir.path().join("paper.tex")));
+    }
+
+    #[test]
+    fn profile_of_repo_file() {
+        let dir = repo(
+            "engine = \"xelatex\"\n[profiles.review]\nengine = \"lualatex\"\n",
+            None,
+        );
+        let settings = Settings::load(dir.path(), Some("review")).unwrap();
+        assert_eq!(settings.engine, Some(Engine::Lualatex));
+
+        let err = Settings::load(dir.path(), Some("draft")).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::ProfileNotFound(_)));
+    }
+}
@@ -0,0 +1,397 @@
+use regex::Regex;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// See https://github.com/James-Yu/LaTeX-Workshop/blob/f65d9e4e437a1fe206842f0ae9245e3181b11ad8/src/components/parser/latexlog.ts
+
+/// TeX wraps the lines of the log at this width, see `max_print_line`
+const MAX_PRINT_LINE: usize = 79;
+
+/// Messages in the log which indicate that another pass is required
+const RERUN_PATTERNS: [&str; 4] = [
+    "Rerun to get",              // cross-references, citations, outlines ...
+    "Label(s) may have changed", // LaTeX kernel
+    "Please rerun LaTeX",        // biblatex
+    "Rerun LaTeX",               // longtable, etc.
+];
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DiagnosticKind {
+    Error,
+    Warning,
+    /// Overfull or underfull boxes
+    BadBox,
+    UndefinedReference,
+    UndefinedCitation,
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticKind::Error => write!(f, "error"),
+            DiagnosticKind::Warning => write!(f, "warning"),
+            DiagnosticKind::BadBox => write!(f, "bad box"),
+            DiagnosticKind::UndefinedReference => write!(f, "undefined reference"),
+            DiagnosticKind::UndefinedCitation => write!(f, "undefined citation"),
+        }
+    }
+}
+
+/// A message extracted from the log of LaTeX engines
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    /// The source file that is being processed when the message is emitted
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        self.kind == DiagnosticKind::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(
+                f,
+                "{}:{}: {}: {}",
+                file.display(),
+                line,
+                self.kind,
+                self.message
+            ),
+            (Some(file), None) => write!(f, "{}: {}: {}", file.display(), self.kind, self.message),
+            (None, _) => write!(f, "{}: {}", self.kind, self.message),
+        }
+    }
+}
+
+struct Patterns {
+    /// `./main.tex:12: Undefined control sequence.`, with `-file-line-error`
+    file_line_error: Regex,
+    /// `! Undefined control sequence.`
+    error: Regex,
+    /// `l.12 \foo`, the line that an error occurs
+    error_line: Regex,
+    /// `LaTeX Warning: ...`, `Package hyperref Warning: ...`, `Class article Warning: ...`
+    warning: Regex,
+    /// `on input line 12`
+    input_line: Regex,
+    /// `Overfull \hbox (12.0pt too wide) in paragraph at lines 3--4`
+    bad_box: Regex,
+    /// `at lines 3--4`, `at line 3`
+    bad_box_line: Regex,
+}
+
+impl Patterns {
+    fn new() -> Self {
+        Patterns {
+            file_line_error: Regex::new(r"^(\S.*?\.\w+):(\d+): (.*)$").unwrap(),
+            error: Regex::new(r"^! (.*)$").unwrap(),
+            error_line: Regex::new(r"^l\.(\d+)").unwrap(),
+            warning: Regex::new(r"^(?:LaTeX(?: (Font))?|Package (\S+)|Class (\S+)) Warning: (.*)$")
+                .unwrap(),
+            input_line: Regex::new(r"on input line (\d+)").unwrap(),
+            bad_box: Regex::new(r"^(?:Over|Under)full \\[hv]box").unwrap(),
+            bad_box_line: Regex::new(r"at lines? (\d+)").unwrap(),
+        }
+    }
+}
+
+/// Read and parse the log file, missing or unreadable log results in no diagnostics
+pub fn parse_file<P: AsRef<Path>>(path: P) -> Vec<Diagnostic> {
+    match fs::read(path.as_ref()) {
+        Ok(log) => parse(&String::from_utf8_lossy(&log)),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Whether the log asks for another pass of compilation
+pub fn needs_rerun<P: AsRef<Path>>(path: P) -> bool {
+    match fs::read(path.as_ref()) {
+        Ok(log) => {
+            let log = String::from_utf8_lossy(&log);
+            RERUN_PATTERNS.iter().any(|pattern| log.contains(pattern))
+        }
+        Err(_) => false,
+    }
+}
+
+/// Parse the content of a log into diagnostics
+pub fn parse(log: &str) -> Vec<Diagnostic> {
+    let patterns = Patterns::new();
+    let lines = unwrap_lines(log);
+    let mut diagnostics = Vec::new();
+    // files that are opened by `(` and closed by `)`, `None` for other parentheses
+    let mut files: Vec<Option<PathBuf>> = Vec::new();
+
+    let mut i = 0;
+    while i < lines.len() {
+        let line = &lines[i];
+        let current_file = files.iter().rev().flatten().next().cloned();
+
+        if let Some(caps) = patterns.file_line_error.captures(line) {
+            diagnostics.push(Diagnostic {
+                kind: DiagnosticKind::Error,
+                file: Some(PathBuf::from(&caps[1])),
+                line: caps[2].parse().ok(),
+                message: caps[3].to_string(),
+            });
+            i += 1;
+            continue;
+        }
+
+        if let Some(caps) = patterns.error.captures(line) {
+            // the line number comes a few lines later
+            let line = lines[i + 1..]
+                .iter()
+                .take(10)
+                .find_map(|line| patterns.error_line.captures(line))
+                .and_then(|caps| caps[1].parse().ok());
+            diagnostics.push(Diagnostic {
+                kind: DiagnosticKind::Error,
+                file: current_file,
+                line,
+                message: caps[1].to_string(),
+            });
+            i += 1;
+            continue;
+        }
+
+        if let Some(caps) = patterns.warning.captures(line) {
+            let name = caps.get(1).or(caps.get(2)).or(caps.get(3));
+            let mut message = caps[4].to_string();
+            i += 1;
+            // collect the continuation lines of the message
+            match name {
+                // `(hyperref)    continued message`
+                Some(name) => {
+                    let prefix = format!("({})", name.as_str());
+                    while i < lines.len() && lines[i].starts_with(&prefix) {
+                        message.push(' ');
+                        message.push_str(lines[i][prefix.len()..].trim());
+                        i += 1;
+                    }
+                }
+                // messages of LaTeX kernel end with a period
+                None => {
+                    while i < lines.len() && !lines[i].is_empty() && !message.ends_with('.') {
+                        message.push(' ');
+                        message.push_str(lines[i].trim());
+                        i += 1;
+                    }
+                }
+            }
+
+            let kind = match message.contains("undefined") {
+                true if message.starts_with("Reference") => DiagnosticKind::UndefinedReference,
+                true if message.starts_with("Citation") => DiagnosticKind::UndefinedCitation,
+                _ => DiagnosticKind::Warning,
+            };
+            let line = patterns
+                .input_line
+                .captures(&message)
+                .and_then(|caps| caps[1].parse().ok());
+            diagnostics.push(Diagnostic {
+                kind,
+                file: current_file,
+                line,
+                message,
+            });
+            continue;
+        }
+
+        if patterns.bad_box.is_match(line) {
+            let line_number = patterns
+                .bad_box_line
+                .captures(line)
+                .and_then(|caps| caps[1].parse().ok());
+            diagnostics.push(Diagnostic {
+                kind: DiagnosticKind::BadBox,
+                file: current_file,
+                line: line_number,
+                message: line.to_string(),
+            });
+            track_files(line, &mut files);
+            i += 1;
+            continue;
+        }
+
+        track_files(line, &mut files);
+        i += 1;
+    }
+
+    diagnostics
+}
+
+/// Join the lines that TeX has wrapped at [`MAX_PRINT_LINE`],
+/// which is counted in bytes by pdftex and in characters by xetex and luatex
+fn unwrap_lines(log: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut buffer = String::new();
+    for line in log.lines() {
+        buffer.push_str(line);
+        let wrapped = line.len() == MAX_PRINT_LINE || line.chars().count() == MAX_PRINT_LINE;
+        if !wrapped {
+            lines.push(std::mem::take(&mut buffer));
+        }
+    }
+    if !buffer.is_empty() {
+        lines.push(buffer);
+    }
+    lines
+}
+
+/// Maintain the stack of opened files, TeX logs `(./chapter.tex` when
+/// it starts reading a file and `)` when it finishes.
+fn track_files(line: &str, files: &mut Vec<Option<PathBuf>>) {
+    let mut chars = line.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '(' => {
+                let mut end = start + 1;
+                while let Some(&(idx, c)) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' {
+                        break;
+                    }
+                    end = idx + c.len_utf8();
+                    chars.next();
+                }
+                let token = &line[start + 1..end];
+                let is_file = Path::new(token).extension().is_some()
+                    && (token.starts_with('.') || token.starts_with('/') || token.contains('/'));
+                files.push(match is_file {
+                    true => Some(PathBuf::from(token)),
+                    false => None,
+                });
+            }
+            ')' => {
+                files.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find<'a>(diagnostics: &'a [Diagnostic], message: &str) -> &'a Diagnostic {
+        diagnostics
+            .iter()
+            .find(|diagnostic| diagnostic.message.contains(message))
+            .unwrap_or_else(|| panic!("no diagnostic of {:?} in {:#?}", message, diagnostics))
+    }
+
+    #[test]
+    fn pdflatex_log() {
+        let diagnostics = parse(include_str!("../tests/fixtures/pdflatex.log"));
+        let chapter = PathBuf::from(
+            "./chapters/a-chapter-whose-file-name-is-long-enough-to-be-wrapped-by-the-engine.tex",
+        );
+        assert_eq!(diagnostics.len(), 7);
+
+        let overfull = find(&diagnostics, "Overfull \\hbox (15.0pt too wide)");
+        assert_eq!(overfull.kind, DiagnosticKind::BadBox);
+        assert_eq!(overfull.file.as_ref(), Some(&chapter));
+        assert_eq!(overfull.line, Some(3));
+
+        let reference = find(&diagnostics, "`sec:missing'");
+        assert_eq!(reference.kind, DiagnosticKind::UndefinedReference);
+        assert_eq!(reference.file.as_ref(), Some(&chapter));
+        assert_eq!(reference.line, Some(7));
+
+        let hyperref = find(&diagnostics, "Token not allowed");
+        assert_eq!(hyperref.kind, DiagnosticKind::Warning);
+        assert!(hyperref
+            .message
+            .ends_with("removing `\\textbf' on input line 8."));
+        assert_eq!(hyperref.line, Some(8));
+
+        let undefined = find(&diagnostics, "Undefined control sequence.");
+        assert!(undefined.is_error());
+        assert_eq!(undefined.file.as_ref(), Some(&chapter));
+        assert_eq!(undefined.line, Some(9));
+
+        let environment = find(&diagnostics, "LaTeX Error: Environment foo undefined.");
+        assert!(environment.is_error());
+        assert_eq!(environment.file, Some(PathBuf::from("./main.tex")));
+        assert_eq!(environment.line, Some(12));
+
+        let citation = find(&diagnostics, "`knuth1984'");
+        assert_eq!(citation.kind, DiagnosticKind::UndefinedCitation);
+        assert_eq!(citation.file, Some(PathBuf::from("./main.tex")));
+        assert_eq!(citation.line, Some(14));
+
+        // wrapped at 79 bytes, which are fewer characters
+        let natbib = find(&diagnostics, "`müller-schönberg-2020'");
+        assert_eq!(natbib.kind, DiagnosticKind::UndefinedCitation);
+        assert_eq!(natbib.line, Some(15));
+    }
+
+    #[test]
+    fn xelatex_log() {
+        let diagnostics = parse(include_str!("../tests/fixtures/xelatex.log"));
+        let chapter = PathBuf::from(
+            "./chapitres/une-introduction-générale-dont-le-nom-est-assez-long-pour-être-coupé.tex",
+        );
+        assert_eq!(diagnostics.len(), 4);
+
+        let underfull = find(&diagnostics, "Underfull \\hbox (badness 10000)");
+        assert_eq!(underfull.kind, DiagnosticKind::BadBox);
+        assert_eq!(underfull.file.as_ref(), Some(&chapter));
+        assert_eq!(underfull.line, Some(5));
+
+        // wrapped at 79 characters, which are more bytes
+        let reference = find(&diagnostics, "`fig:schéma-général-de-l’architecture'");
+        assert_eq!(reference.kind, DiagnosticKind::UndefinedReference);
+        assert_eq!(reference.file.as_ref(), Some(&chapter));
+        assert_eq!(reference.line, Some(9));
+
+        let error = find(&diagnostics, "LaTeX Error: Environment théorème undefined.");
+        assert!(error.is_error());
+        assert_eq!(error.file, Some(PathBuf::from("./main.tex")));
+        assert_eq!(error.line, Some(17));
+
+        let font = find(&diagnostics, "Font shape `TU/lmr/m/scit' undefined");
+        assert_eq!(font.kind, DiagnosticKind::Warning);
+        assert_eq!(font.line, Some(21));
+    }
+
+    #[test]
+    fn unwrap_lines_by_bytes_and_chars() {
+        let ascii = "a".repeat(MAX_PRINT_LINE);
+        let accented = "é".repeat(MAX_PRINT_LINE);
+        let log = format!("{}\nb\n{}\nc\nshort\nd\n", ascii, accented);
+        assert_eq!(
+            unwrap_lines(&log),
+            vec![
+                format!("{}b", ascii),
+                format!("{}c", accented),
+                "short".into(),
+                "d".into()
+            ]
+        );
+    }
+
+    #[test]
+    fn rerun_is_detected() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let log = dir.path().join("main.log");
+        fs::write(
+            &log,
+            "LaTeX Warning: Label(s) may have changed. Rerun to get cross-references right.\n",
+        )
+        .unwrap();
+        assert!(needs_rerun(&log));
+        fs::write(&log, "Output written on main.pdf (1 page).\n").unwrap();
+        assert!(!needs_rerun(&log));
+        assert!(!needs_rerun(dir.path().join("missing.log")));
+    }
+}
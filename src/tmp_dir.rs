@@ -0,0 +1,135 @@
+use git2::Repository;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Every tmp dir is named `git_latexdiff_<timestamp>`
+pub const PREFIX: &str = "git_latexdiff_";
+
+/// Whether `path` is a tmp dir of ours placed in `parent`, including those left by other runs
+pub fn is_tmp_dir(path: &Path, parent: &Path) -> bool {
+    path.parent() == Some(parent)
+        && path.is_dir()
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(PREFIX))
+}
+
+/// Guard of the directory holding the intermediate files, which is removed
+/// once the guard is dropped, no matter the run succeeds, fails or panics
+pub struct TmpDir {
+    path: PathBuf,
+    /// Keep everything, i.e. `--no-clean`
+    keep: bool,
+    /// Keep the output of the tools in `logs` only
+    keep_logs: bool,
+}
+
+impl TmpDir {
+    /// Create `path` with the `old` and `new` checkouts in it
+    pub fn create(path: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(path.join("old"))?;
+        fs::create_dir_all(path.join("new"))?;
+        Ok(TmpDir {
+            path,
+            keep: false,
+            keep_logs: false,
+        })
+    }
+
+    pub fn keep(mut self, on: bool) -> Self {
+        self.keep = on;
+        self
+    }
+
+    pub fn keep_logs(mut self, on: bool) -> Self {
+        self.keep_logs = on;
+        self
+    }
+
+    /// Add the tmp dirs to `info/exclude` of `repo` if they are placed in its worktree,
+    /// so that they do not show up as untracked files
+    pub fn exclude_from(&self, repo: &Repository) -> io::Result<()> {
+        let workdir = match repo.workdir() {
+            Some(workdir) => fs::canonicalize(workdir)?,
+            None => return Ok(()),
+        };
+        let relative = match fs::canonicalize(&self.path)?.strip_prefix(&workdir) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => return Ok(()),
+        };
+        if repo.is_path_ignored(&relative).unwrap_or(false) {
+            return Ok(());
+        }
+
+        // cover the tmp dirs of later runs as well
+        let parent = relative.parent().unwrap_or(Path::new(""));
+        let mut pattern = String::from("/");
+        for component in parent.components() {
+            pattern.push_str(&component.as_os_str().to_string_lossy());
+            pattern.push('/');
+        }
+        pattern.push_str(PREFIX);
+        pattern.push_str("*/");
+
+        let exclude = common_dir(repo).join("info").join("exclude");
+        if let Ok(content) = fs::read_to_string(&exclude) {
+            if content.lines().any(|line| line == pattern) {
+                return Ok(());
+            }
+        }
+        fs::create_dir_all(exclude.parent().unwrap())?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&exclude)?;
+        writeln!(file, "# intermediate files of git-latexdiff\n{}", pattern)?;
+        info!("Excluded {} from git in {}", pattern, exclude.display());
+        Ok(())
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        match self.keep_logs {
+            true => {
+                fs::remove_dir_all(self.path.join("old"))?;
+                fs::remove_dir_all(self.path.join("new"))?;
+                info!(
+                    "Output of the tools is kept in {}",
+                    self.path.join("logs").display()
+                );
+                Ok(())
+            }
+            false => fs::remove_dir_all(&self.path),
+        }
+    }
+}
+
+impl Drop for TmpDir {
+    fn drop(&mut self) {
+        if self.keep {
+            info!("Intermediate files are kept in {}", self.path.display());
+            return;
+        }
+        // check dangerous operation
+        if self.path.parent().is_none() {
+            warn!("Refuse to remove {}", self.path.display());
+            return;
+        }
+        if let Err(e) = self.remove() {
+            warn!("Fail to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// The git dir shared by all the worktrees, which holds `info/exclude`
+fn common_dir(repo: &Repository) -> PathBuf {
+    let git_dir = repo.path();
+    // a linked worktree refers to the common dir in `commondir`
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common_dir) => git_dir.join(common_dir.trim_end()),
+        Err(_) => git_dir.to_path_buf(),
+    }
+}
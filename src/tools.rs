@@ -0,0 +1,285 @@
+use crate::args::Args;
+use crate::error::{Error, ErrorKind};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// External tools that we invoke
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, ValueEnum, Deserialize)]
+// toml does not take enums as the keys of tables, parse the names as strings instead
+#[serde(try_from = "String")]
+pub enum ToolName {
+    Pdflatex,
+    Xelatex,
+    Lualatex,
+    Bibtex,
+    Biber,
+    Latexpand,
+    /// Only run by latexmk, the native builder does not make indexes
+    Makeindex,
+    Latexmk,
+    Latexdiff,
+    Fzf,
+}
+
+impl ToolName {
+    pub const ALL: [ToolName; 10] = [
+        ToolName::Pdflatex,
+        ToolName::Xelatex,
+        ToolName::Lualatex,
+        ToolName::Bibtex,
+        ToolName::Biber,
+        ToolName::Latexpand,
+        ToolName::Makeindex,
+        ToolName::Latexmk,
+        ToolName::Latexdiff,
+        ToolName::Fzf,
+    ];
+
+    /// Name of the executable, which is searched in $PATH if no path is configured
+    pub fn binary(&self) -> &'static str {
+        match self {
+            ToolName::Pdflatex => "pdflatex",
+            ToolName::Xelatex => "xelatex",
+            ToolName::Lualatex => "lualatex",
+            ToolName::Bibtex => "bibtex",
+            ToolName::Biber => "biber",
+            ToolName::Latexpand => "latexpand",
+            ToolName::Makeindex => "makeindex",
+            ToolName::Latexmk => "latexmk",
+            ToolName::Latexdiff => "latexdiff",
+            ToolName::Fzf => "fzf",
+        }
+    }
+
+    /// Variable holding the command of the tool in `latexmkrc`,
+    /// `None` if latexmk does not run the tool
+    pub fn latexmk_var(&self) -> Option<&'static str> {
+        match self {
+            ToolName::Pdflatex => Some("pdflatex"),
+            ToolName::Xelatex => Some("xelatex"),
+            ToolName::Lualatex => Some("lualatex"),
+            ToolName::Bibtex => Some("bibtex"),
+            ToolName::Biber => Some("biber"),
+            ToolName::Makeindex => Some("makeindex"),
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<String> for ToolName {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        ToolName::from_str(&value, true)
+    }
+}
+
+impl fmt::Display for ToolName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.binary())
+    }
+}
+
+/// How to invoke a tool, e.g. `[tools.pdflatex]` of the config file
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tool {
+    /// The executable, found in $PATH by the name of the tool if not given
+    pub path: Option<PathBuf>,
+    /// Extra args, placed before the args given by us
+    pub args: Vec<String>,
+    /// Extra environment variables
+    pub env: HashMap<String, String>,
+}
+
+impl Tool {
+    /// Fill the missing fields with those of `lower`
+    pub fn or(self, lower: Tool) -> Tool {
+        let mut env = lower.env;
+        env.extend(self.env);
+        Tool {
+            path: self.path.or(lower.path),
+            args: match self.args.is_empty() {
+                true => lower.args,
+                false => self.args,
+            },
+            env,
+        }
+    }
+}
+
+/// Registry of the external tools, tools that are not configured are used as is
+#[derive(Clone, Debug, Default)]
+pub struct Tools {
+    tools: HashMap<ToolName, Tool>,
+}
+
+impl Tools {
+    pub fn new(tools: HashMap<ToolName, Tool>) -> Self {
+        Tools { tools }
+    }
+
+    pub fn set_path(&mut self, name: ToolName, path: PathBuf) {
+        self.tools.entry(name).or_default().path = Some(path);
+    }
+
+    /// Make the relative paths of the tools absolute against `dir`, since the tools
+    /// are spawned in the project dir rather than where they are given.
+    /// A bare name is still searched in $PATH.
+    pub fn root_at(&mut self, dir: &Path) {
+        for tool in self.tools.values_mut() {
+            if let Some(path) = &tool.path {
+                if path.is_relative() && path.components().count() > 1 {
+                    tool.path = Some(dir.join(path));
+                }
+            }
+        }
+    }
+
+    /// Configured path of the tool, or its name
+    pub fn path(&self, name: ToolName) -> PathBuf {
+        match self.tools.get(&name).and_then(|tool| tool.path.clone()) {
+            Some(path) => path,
+            None => PathBuf::from(name.binary()),
+        }
+    }
+
+    /// Find the executable of the tool
+    pub fn resolve(&self, name: ToolName) -> Result<PathBuf, Error> {
+        let path = self.path(name);
+        which::which(&path)
+            .map_err(|_| Error::new(ErrorKind::BinaryNotFound(path.display().to_string())))
+    }
+
+    /// Description of the configured tools in a stable order, which tells
+    /// whether the tools have changed between two runs
+    pub fn fingerprint(&self) -> String {
+        let mut fingerprint = String::new();
+        for name in ToolName::ALL {
+            if let Some(tool) = self.tools.get(&name) {
+                let env: BTreeMap<_, _> = tool.env.iter().collect();
+                fingerprint.push_str(&format!(
+                    "{}: path={:?} args={:?} env={:?}\n",
+                    name, tool.path, tool.args, env
+                ));
+            }
+        }
+        fingerprint
+    }
+
+    /// Command that runs the tool with the configured args and environment
+    pub fn command(&self, name: ToolName) -> Command {
+        let mut command = Command::new(self.path(name));
+        if let Some(tool) = self.tools.get(&name) {
+            command.args(&tool.args).envs(&tool.env);
+        }
+        command
+    }
+
+    /// Command that runs latexmk, which in turn runs the configured engines,
+    /// bibliography backends and makeindex rather than its defaults.
+    /// The environment of those tools is given to latexmk as a whole.
+    pub fn latexmk_command(&self) -> Command {
+        let mut command = self.command(ToolName::Latexmk);
+        for name in ToolName::ALL {
+            let (var, tool) = match (name.latexmk_var(), self.tools.get(&name)) {
+                (Some(var), Some(tool)) => (var, tool),
+                _ => continue,
+            };
+            command.envs(&tool.env);
+            if tool.path.is_none() && tool.args.is_empty() {
+                continue;
+            }
+            // latexmk runs the commands through the shell, and `-e` takes perl code
+            let mut words = vec![self.path(name).to_string_lossy().into_owned()];
+            words.extend(tool.args.iter().cloned());
+            let line = format!("{} %O %S", shell_words::join(words));
+            let line = line.replace('\\', "\\\\").replace('\'', "\\'");
+            command.arg("-e").arg(format!("${}='{}'", var, line));
+        }
+        command
+    }
+}
+
+impl From<&Args> for Tools {
+    fn from(args: &Args) -> Self {
+        let mut tools = Tools::new(args.tools.clone());
+        if let Some(path) = &args.latexdiff_path {
+            tools.set_path(ToolName::Latexdiff, path.clone());
+        }
+        for (name, path) in &args.tool_path {
+            tools.set_path(*name, path.clone());
+        }
+        tools
+    }
+}
+
+/// Parse `name=path` of `--tool-path`
+pub fn parse_tool_path(value: &str) -> Result<(ToolName, PathBuf), String> {
+    let (name, path) = value
+        .split_once('=')
+        .ok_or_else(|| String::from("expect <NAME>=<PATH>"))?;
+    let name = ToolName::from_str(name, true)?;
+    Ok((name, PathBuf::from(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn latexmk_runs_the_configured_tools() {
+        let mut tools = HashMap::new();
+        tools.insert(
+            ToolName::Pdflatex,
+            Tool {
+                path: Some(PathBuf::from("/opt/tex live/pdflatex")),
+                args: vec![String::from("-shell-escape")],
+                env: HashMap::from([(String::from("TEXINPUTS"), String::from(".:"))]),
+            },
+        );
+        tools.insert(
+            ToolName::Makeindex,
+            Tool {
+                path: Some(PathBuf::from("/opt/it's/makeindex")),
+                ..Tool::default()
+            },
+        );
+        let command = Tools::new(tools).latexmk_command();
+
+        assert_eq!(
+            args(&command),
+            vec![
+                "-e",
+                "$pdflatex='\\'/opt/tex live/pdflatex\\' -shell-escape %O %S'",
+                "-e",
+                "$makeindex='\\'/opt/it\\'\\\\\\'\\'s/makeindex\\' %O %S'",
+            ]
+        );
+        let env: Vec<_> = command.get_envs().collect();
+        assert_eq!(
+            env,
+            vec![(
+                std::ffi::OsStr::new("TEXINPUTS"),
+                Some(std::ffi::OsStr::new(".:"))
+            )]
+        );
+    }
+
+    #[test]
+    fn latexmk_keeps_its_defaults() {
+        let command = Tools::default().latexmk_command();
+        assert!(args(&command).is_empty());
+    }
+}
@@ -1,45 +1,42 @@
+use crate::tools::{ToolName, Tools};
 use crossterm::style::Stylize;
 use std::io::Write;
 use which::which;
 
-pub fn health_check() {
-    // TODO: executable can also be passed by config
+pub fn health_check(tools: &Tools) {
     let mut stdout = std::io::stdout();
 
-    let bins = vec![
-        "perl",
-        "latexdiff",
-        "latexdiff-so",
-        "latexpand",
-        "pdflatex",
-        "xelatex",
-        "luatex",
-        "bibtex",
-    ];
-
     let mut ready = true;
-    for bin in bins {
-        let msg;
-        match which(bin) {
-            Ok(path) => {
-                msg = path.display().to_string().green();
-            }
-            Err(_) => {
-                msg = String::from("Not found in $PATH").red();
+    // latexdiff is a perl script
+    let msg = match which("perl") {
+        Ok(path) => path.display().to_string().green(),
+        Err(_) => {
+            ready = false;
+            String::from("Not found in $PATH").red()
+        }
+    };
+    writeln!(stdout, "Binary for perl: {}", msg).unwrap();
+
+    for name in ToolName::ALL {
+        // the others are only needed by some of the options
+        let required = matches!(
+            name,
+            ToolName::Pdflatex | ToolName::Bibtex | ToolName::Latexdiff
+        );
+        let msg = match tools.resolve(name) {
+            Ok(path) => path.display().to_string().green(),
+            Err(_) if required => {
                 ready = false;
+                format!("{} not found", tools.path(name).display()).red()
             }
-        }
-        writeln!(stdout, "Binary for {}: {}", bin, msg).unwrap();
+            Err(_) => format!("{} not found", tools.path(name).display()).yellow(),
+        };
+        writeln!(stdout, "Binary for {}: {}", name, msg).unwrap();
     }
 
-    let msg;
-    match ready {
-        true => {
-            msg = "✓".green();
-        }
-        false => {
-            msg = "✘".red();
-        }
-    }
+    let msg = match ready {
+        true => "✓".green(),
+        false => "✘".red(),
+    };
     writeln!(stdout, "Ready? {}", msg).unwrap();
 }
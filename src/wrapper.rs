@@ -1,23 +1,50 @@
 use crate::error::{Error, ErrorKind};
 use crate::wrapper::CommitWrapper::Commit;
 use git2::{Oid, Repository};
+use std::fmt;
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum CommitWrapper {
+    /// Working directory, including uncommitted and untracked files
+    Worktree,
     Index,
     Commit(Oid),
 }
 
 impl CommitWrapper {
-    pub fn parse(repo: &Repository, hash: &String) -> std::result::Result<CommitWrapper, Error> {
-        return match hash.to_lowercase().as_str() {
+    pub fn parse(repo: &Repository, hash: &str) -> std::result::Result<CommitWrapper, Error> {
+        match hash.to_lowercase().as_str() {
+            "worktree" | "." => Ok(CommitWrapper::Worktree),
             "index" => Ok(CommitWrapper::Index),
             _ => {
-                let res = repo.revparse_single(&hash);
+                let res = repo.revparse_single(hash);
                 match res {
                     Ok(x) => Ok(Commit(x.id())),
                     Err(_) => Err(Error::new(ErrorKind::InvalidCommitHash)),
                 }
             }
-        };
+        }
+    }
+}
+
+impl CommitWrapper {
+    /// Full form of the version, which [`CommitWrapper::parse`] takes back
+    pub fn key(&self) -> String {
+        match self {
+            CommitWrapper::Worktree => String::from("worktree"),
+            CommitWrapper::Index => String::from("index"),
+            CommitWrapper::Commit(oid) => oid.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CommitWrapper {
+    /// Short form of the version, e.g. in the name of the output
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommitWrapper::Worktree => write!(f, "worktree"),
+            CommitWrapper::Index => write!(f, "index"),
+            CommitWrapper::Commit(oid) => write!(f, "{}", &oid.to_string()[0..7]),
+        }
     }
 }